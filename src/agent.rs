@@ -0,0 +1,268 @@
+//! A decryption agent, which holds unlocked secret keys in a separate process and performs only the
+//! X25519 precompute step for its clients, over a Unix socket.
+//!
+//! Request:  `01` | source public key (32 bytes) | target public key (32 bytes)
+//! Response: `00` | precomputed key (32 bytes)
+//!       or: `01` | message length (u16, big endian) | UTF-8 message
+//!
+//! Request:  `02`
+//! Response: `00` | key count (u16, big endian) | public keys (32 bytes each)
+
+use std::{fs, io::{self, Read, Write}, sync::Arc, thread,
+          os::unix::{fs::{DirBuilderExt, PermissionsExt}, io::AsRawFd,
+                     net::{UnixListener, UnixStream}}};
+use anyhow::Context;
+use sodiumoxide::crypto::box_::{self, PRECOMPUTEDKEYBYTES};
+
+use crate::{error::Error, key::{PrecomputedKey, PublicKey, SecretKey},
+            keystore::{KeyStore, MemoryStore}};
+
+/// The environment variable naming the agent's socket, like ssh's `SSH_AUTH_SOCK`.
+pub const AGENT_SOCKET_ENV: &str = "TURNSTILE_AGENT_SOCK";
+
+const PRECOMPUTE: u8 = 0x01;
+const CANDIDATES: u8 = 0x02;
+const OK: u8 = 0x00;
+const ERROR: u8 = 0x01;
+/// An error whose message is the base62 public key which the agent does not hold.
+const NO_KEY: u8 = 0x02;
+
+/// The agent's socket, by default, is in the key directory, which only its owner can use.
+pub fn default_socket_path(keydir: &str) -> String {
+    format!("{keydir}/agent.sock")
+}
+
+/// Load and unlock every secret key in `store`, then serve clients until killed.  Clients which
+/// are not served are reported to `log`.
+pub fn agent(store: &dyn KeyStore, socket_path: &str, output: &mut dyn Write, log: &mut dyn Write)
+                                                                        -> anyhow::Result<()> {
+    let keys = MemoryStore::load(store)?;
+
+    let listener = listen(socket_path)?;
+    writeln!(output, "agent holding {} keys, listening on {socket_path}", keys.len())?;
+    output.flush()?;
+
+    serve_clients(listener, Arc::new(keys), log);
+    Ok(())
+}
+
+/// Serve each client of our own user in its own thread.  A failure to accept one client, such as
+/// running out of file descriptors, is reported rather than stopping the agent.
+fn serve_clients(listener: UnixListener, keys: Arc<MemoryStore>, log: &mut dyn Write) {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                report(log, format_args!("unable to accept a client: {e}"));
+                continue;
+            },
+        };
+        match peer_uid(&stream) {
+            Ok(uid) if uid == unsafe { libc::geteuid() } => (),
+            Ok(uid) => {
+                report(log, format_args!("refused a client running as uid {uid}"));
+                continue;
+            },
+            Err(e) => {
+                report(log, format_args!("unable to identify a client: {e}"));
+                continue;
+            },
+        }
+        let keys = Arc::clone(&keys);
+        thread::spawn(move || serve(stream, &keys));
+    }
+}
+
+/// A log which cannot be written must not stop the agent, as any other user could then stop it by
+/// connecting.
+fn report(log: &mut dyn Write, message: std::fmt::Arguments) {
+    let _ = writeln!(log, "agent: {message}").and_then(|()| log.flush());
+}
+
+/// Bind the socket, replacing a stale socket left by an agent which is no longer running.
+///
+/// The socket is bound in a new directory which only we can use, and only moved into place once it
+/// is 0600, so that no other user can connect to it in between.
+fn listen(socket_path: &str) -> anyhow::Result<UnixListener> {
+    if fs::metadata(socket_path).is_ok() {
+        if UnixStream::connect(socket_path).is_ok() {
+            return Err(anyhow::anyhow!("an agent is already listening on '{socket_path}'"));
+        }
+        fs::remove_file(socket_path)
+            .context(format!("unable to remove stale socket '{socket_path}'"))?;
+    }
+    let private_dir = format!("{socket_path}.{}.d", std::process::id());
+    fs::DirBuilder::new().mode(0o700).create(&private_dir)
+        .context(format!("unable to create '{private_dir}'"))?;
+    let private_path = format!("{private_dir}/agent.sock");
+    let bound = UnixListener::bind(&private_path)
+        .and_then(|listener| {
+            fs::set_permissions(&private_path, fs::Permissions::from_mode(0o600))?;
+            fs::rename(&private_path, socket_path)?;
+            Ok(listener)
+        });
+    let _ = fs::remove_file(&private_path);
+    let _ = fs::remove_dir(&private_dir);
+    bound.context(format!("unable to listen on '{socket_path}'"))
+}
+
+/// The user id of the process at the other end of a Unix socket.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn peer_uid(stream: &UnixStream) -> io::Result<u32> {
+    let mut cred = libc::ucred { pid: 0, uid: 0, gid: 0 };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(stream.as_raw_fd(), libc::SOL_SOCKET, libc::SO_PEERCRED,
+                                        &mut cred as *mut libc::ucred as *mut _, &mut len)
+    };
+    match result {
+        0 => Ok(cred.uid),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn peer_uid(stream: &UnixStream) -> io::Result<u32> {
+    let (mut uid, mut gid) = (0, 0);
+    match unsafe { libc::getpeereid(stream.as_raw_fd(), &mut uid, &mut gid) } {
+        0 => Ok(uid),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// Answer one client's requests until it disconnects.
+fn serve(mut stream: UnixStream, keys: &MemoryStore) -> anyhow::Result<()> {
+    loop {
+        let mut op = [0u8; 1];
+        if stream.read(&mut op)? == 0 {
+            return Ok(());
+        }
+        match op[0] {
+            PRECOMPUTE => {
+                let mut source_pkey = [0u8; 32];
+                let mut target_pkey = [0u8; 32];
+                stream.read_exact(&mut source_pkey)?;
+                stream.read_exact(&mut target_pkey)?;
+                let target_pkey = PublicKey::from_bytes(target_pkey);
+
+                match keys.precompute(&PublicKey::from_bytes(source_pkey), &target_pkey) {
+                    Ok(symkey) => {
+                        stream.write_all(&[OK])?;
+                        stream.write_all(symkey.as_bytes())?;
+                    }
+                    Err(_) => write_error(&mut stream, NO_KEY, &target_pkey.to_string())?,
+                }
+            }
+            CANDIDATES => {
+                let pkeys = keys.candidates()?;
+                stream.write_all(&[OK])?;
+                stream.write_all(&(pkeys.len() as u16).to_be_bytes())?;
+                for pkey in pkeys {
+                    stream.write_all(pkey.as_bytes())?;
+                }
+            }
+            op => return write_error(&mut stream, ERROR, &format!("unknown request {op:#04x}")),
+        }
+    }
+}
+
+fn write_error(stream: &mut UnixStream, status: u8, message: &str) -> anyhow::Result<()> {
+    stream.write_all(&[status])?;
+    stream.write_all(&(message.len() as u16).to_be_bytes())?;
+    stream.write_all(message.as_bytes())?;
+    Ok(())
+}
+
+/// The secret keys held by the agent listening on a socket.  They never leave the agent, which
+/// only returns precomputed keys.
+pub struct AgentStore {
+    pub socket_path: String,
+}
+
+impl AgentStore {
+    pub fn new(socket_path: &str) -> Self {
+        AgentStore { socket_path: socket_path.to_string() }
+    }
+
+    /// Send a request, returning the stream positioned after a successful response's status.
+    fn request(&self, request: &[u8]) -> anyhow::Result<UnixStream> {
+        let mut stream = UnixStream::connect(&self.socket_path)
+            .context(format!("unable to connect to the agent on '{}'", self.socket_path))?;
+        stream.write_all(request)?;
+
+        let mut status = [0u8; 1];
+        stream.read_exact(&mut status)?;
+        if status[0] == OK {
+            return Ok(stream);
+        }
+        let mut len_buf = [0u8; 2];
+        stream.read_exact(&mut len_buf)?;
+        let mut message = vec![0u8; u16::from_be_bytes(len_buf) as usize];
+        stream.read_exact(&mut message)?;
+        let message = String::from_utf8_lossy(&message).into_owned();
+        if status[0] == NO_KEY {
+            return Err(anyhow::Error::new(Error::KeyNotFound(message))
+                .context(format!("the agent on '{}' does not hold the key", self.socket_path)));
+        }
+        Err(anyhow::anyhow!(message))
+    }
+}
+
+impl KeyStore for AgentStore {
+    fn find(&self, pkey: &PublicKey) -> anyhow::Result<SecretKey> {
+        Err(anyhow::anyhow!("the agent does not reveal the secret key for {pkey}"))
+    }
+
+    fn candidates(&self) -> anyhow::Result<Vec<PublicKey>> {
+        let mut stream = self.request(&[CANDIDATES])?;
+        let mut count_buf = [0u8; 2];
+        stream.read_exact(&mut count_buf)?;
+        let mut pkeys = Vec::new();
+        for _ in 0..u16::from_be_bytes(count_buf) {
+            let mut pkey = [0u8; 32];
+            stream.read_exact(&mut pkey)?;
+            pkeys.push(PublicKey::from_bytes(pkey));
+        }
+        Ok(pkeys)
+    }
+
+    fn precompute(&self, source_pkey: &PublicKey, target_pkey: &PublicKey)
+                                                            -> anyhow::Result<PrecomputedKey> {
+        let mut request = vec![PRECOMPUTE];
+        request.extend_from_slice(source_pkey.as_bytes());
+        request.extend_from_slice(target_pkey.as_bytes());
+        let mut stream = self.request(&request)?;
+        let mut symkey = box_::PrecomputedKey([0u8; PRECOMPUTEDKEYBYTES]);
+        stream.read_exact(&mut symkey.0)?;
+        Ok(symkey.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_agent() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket_path = dir.path().join("agent.sock").to_str().unwrap().to_string();
+        let (source_pkey, source_skey) = box_::gen_keypair();
+        let (target_pkey, target_skey) = box_::gen_keypair();
+        let mut keys = MemoryStore::new();
+        keys.insert(target_skey.into());
+
+        let listener = listen(&socket_path).unwrap();
+        assert_eq!(fs::metadata(&socket_path).unwrap().permissions().mode() & 0o777, 0o600);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+        thread::spawn(move || serve_clients(listener, Arc::new(keys), &mut io::sink()));
+
+        let store = AgentStore::new(&socket_path);
+        let symkey = store.precompute(&source_pkey.into(), &target_pkey.into()).unwrap();
+        assert_eq!(symkey.as_bytes(), &box_::precompute(&target_pkey, &source_skey).0);
+        assert_eq!(store.candidates().unwrap(), vec![target_pkey.into()]);
+        assert!(store.precompute(&source_pkey.into(), &source_pkey.into()).is_err());
+
+        let stream = UnixStream::connect(&socket_path).unwrap();
+        assert_eq!(peer_uid(&stream).unwrap(), unsafe { libc::geteuid() });
+    }
+}
@@ -0,0 +1,341 @@
+//! `AsyncWrite` and `AsyncRead` adapters, the tokio counterparts of `stream`, which write and read
+//! exactly the same format.
+
+use std::{io, pin::Pin, task::{ready, Context, Poll}};
+
+use sodiumoxide::crypto::box_::{self, Nonce, PrecomputedKey, PublicKey, SecretKey};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+
+use crate::{common::*, decrypt::{open_chunk, read_preamble, read_symkey, Summary},
+            encrypt::{write_chunk, write_header}, error::Error, key, keystore::KeyStore,
+            memory::Locked, threshold::SEALED_SHARE};
+
+/// Encrypts everything written to it, in the same format as `encrypt::encrypt`.
+///
+/// Plaintext is buffered until there is a whole chunk, or until `flush`, which writes a shorter
+/// chunk.  There is no asynchronous drop, so `shutdown` must be called to write the end marker.
+pub struct AsyncEncryptWriter<W: AsyncWrite + Unpin> {
+    output: W,
+    symkey: Locked<PrecomputedKey>,
+    initial_nonce: Nonce,
+    chunk_num: u64,
+    buf: Locked<[u8]>,
+    len: usize,
+    /// Header, chunks or end marker, not yet written to `output`.
+    pending: Vec<u8>,
+    written: usize,
+    finished: bool,
+}
+
+impl<W: AsyncWrite + Unpin> AsyncEncryptWriter<W> {
+    /// Prepare the header for `target_key`, from a new ephemeral keypair.
+    /// (`Encryptor::async_writer` can draw the ephemeral key and initial nonce from another `Rng`.)
+    pub fn new(output: W, target_key: &key::PublicKey) -> Self {
+        AsyncEncryptWriter::with_keys(output, &(*target_key).into(), &box_::gen_keypair().1,
+                                                                            &box_::gen_nonce())
+    }
+
+    pub(crate) fn with_keys(output: W, target_pkey: &PublicKey, source_skey: &SecretKey,
+                                                                initial_nonce: &Nonce) -> Self {
+        let mut pending = Vec::new();
+        write_header(&source_skey.public_key(), target_pkey, initial_nonce, &mut pending)
+            .expect("writing to a Vec cannot fail");
+        AsyncEncryptWriter {
+            output,
+            symkey: Locked::new(Box::new(box_::precompute(target_pkey, source_skey))),
+            initial_nonce: *initial_nonce,
+            chunk_num: 0,
+            buf: Locked::buffer(MAX_PLAINTEXT_CHUNK),
+            len: 0,
+            pending,
+            written: 0,
+            finished: false,
+        }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.output
+    }
+
+    /// Encipher the buffered plaintext as the next chunk, for writing.
+    fn seal_buffered(&mut self) {
+        if self.len == 0 {
+            return;
+        }
+        let chunk_nonce = calculate_chunk_nonce(&self.initial_nonce, self.chunk_num);
+        write_chunk(&mut self.buf[..self.len], &chunk_nonce, &self.symkey, &mut self.pending)
+            .expect("writing to a Vec cannot fail");
+        self.chunk_num += 1;
+        self.len = 0;
+    }
+
+    fn poll_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.written < self.pending.len() {
+            let pending = &self.pending[self.written..];
+            let n = ready!(Pin::new(&mut self.output).poll_write(cx, pending))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.written += n;
+        }
+        self.pending.clear();
+        self.written = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for AsyncEncryptWriter<W> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, plaintext: &[u8])
+                                                                    -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.finished {
+            return Poll::Ready(Err(io::Error::other("the stream has been shut down")));
+        }
+        if this.len == this.buf.len() {
+            this.seal_buffered();
+        }
+        ready!(this.poll_pending(cx))?;
+        let n = plaintext.len().min(this.buf.len() - this.len);
+        this.buf[this.len..this.len + n].copy_from_slice(&plaintext[..n]);
+        this.len += n;
+        Poll::Ready(Ok(n))
+    }
+
+    /// Write the buffered plaintext as a chunk, so that everything written so far can be decrypted.
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        this.seal_buffered();
+        ready!(this.poll_pending(cx))?;
+        Pin::new(&mut this.output).poll_flush(cx)
+    }
+
+    /// Write any buffered plaintext, and the end marker, then shut down the inner writer.
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.finished {
+            this.seal_buffered();
+            this.pending.extend_from_slice(&0u16.to_be_bytes()); // 0x0000 signifies end
+            this.finished = true;
+        }
+        ready!(this.poll_pending(cx))?;
+        Pin::new(&mut this.output).poll_shutdown(cx)
+    }
+}
+
+/// Decrypts a stream as it is read, in either format.
+///
+/// Each chunk is authenticated before any of its plaintext is returned.  A stream which ends
+/// without the end marker reads as ending normally, so check `summary` afterwards.
+pub struct AsyncDecryptReader<R: AsyncRead + Unpin> {
+    input: R,
+    symkey: Locked<PrecomputedKey>,
+    initial_nonce: Nonce,
+    chunk_num: u64,
+    buf: Locked<[u8]>,
+    /// How much of the next chunk's length, then of the chunk itself, has been read.
+    len_buf: [u8; 2],
+    len: Option<usize>,
+    filled: usize,
+    /// The unread plaintext in `buf`.
+    start: usize,
+    end: usize,
+    /// Set once the stream has ended, with whether it had the end marker.
+    ended: Option<bool>,
+}
+
+impl<R: AsyncRead + Unpin> AsyncDecryptReader<R> {
+    /// Read the header, finding the precomputed key in `store`.  (The store is not asynchronous,
+    /// so this may block, for example while asking for a passphrase.)
+    pub async fn new(mut input: R, store: &dyn KeyStore) -> anyhow::Result<Self> {
+        let header = read_header_bytes(&mut input).await?;
+        let (symkey, initial_nonce) = read_symkey(&mut header.as_slice(), store)?;
+        Ok(AsyncDecryptReader {
+            input,
+            symkey,
+            initial_nonce,
+            chunk_num: 0,
+            buf: Locked::buffer(MAX_CIPHERTEXT_CHUNK),
+            len_buf: [0u8; 2],
+            len: None,
+            filled: 0,
+            start: 0,
+            end: 0,
+            ended: None,
+        })
+    }
+
+    /// How the stream ended, once reading has returned no more plaintext.
+    pub fn summary(&self) -> Option<Summary> {
+        self.ended.map(|end_marker| Summary { chunks: self.chunk_num, end_marker })
+    }
+
+    pub fn into_inner(self) -> R {
+        self.input
+    }
+
+    /// Read the next chunk, and decipher it in place, returning false once the stream has ended.
+    fn poll_chunk(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<bool>> {
+        let chunk_num = self.chunk_num;
+        let len = loop {
+            if let Some(len) = self.len {
+                break len;
+            }
+            let n = ready!(poll_read_into(&mut self.input, cx, &mut self.len_buf[self.filled..]))?;
+            match (n, self.filled) {
+                (0, 0) => {
+                    self.ended = Some(false);
+                    return Poll::Ready(Ok(false));
+                },
+                (0, _) => return Poll::Ready(Err(Error::Truncated { chunk: chunk_num }.into())),
+                _ => self.filled += n,
+            }
+            if self.filled == self.len_buf.len() {
+                let len = u16::from_be_bytes(self.len_buf) as usize;
+                if len == 0 {
+                    self.ended = Some(true);
+                    return Poll::Ready(Ok(false));
+                }
+                self.len = Some(len);
+                self.filled = 0;
+            }
+        };
+
+        while self.filled < len {
+            let n = ready!(poll_read_into(&mut self.input, cx, &mut self.buf[self.filled..len]))?;
+            if n == 0 {
+                return Poll::Ready(Err(Error::Truncated { chunk: chunk_num }.into()));
+            }
+            self.filled += n;
+        }
+        let plaintext_len = open_chunk(&mut self.buf[..len], &self.symkey, &self.initial_nonce,
+                                                                                chunk_num)?;
+        self.chunk_num += 1;
+        self.len = None;
+        self.filled = 0;
+        self.start = BOX_OVERHEAD;
+        self.end = BOX_OVERHEAD + plaintext_len;
+        Poll::Ready(Ok(true))
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for AsyncDecryptReader<R> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, plaintext: &mut ReadBuf<'_>)
+                                                                    -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        while this.start == this.end {
+            if this.ended.is_some() || !ready!(this.poll_chunk(cx))? {
+                return Poll::Ready(Ok(()));
+            }
+        }
+        let n = plaintext.remaining().min(this.end - this.start);
+        plaintext.put_slice(&this.buf[this.start..this.start + n]);
+        this.start += n;
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Read into `buf`, returning how much was read.
+fn poll_read_into<R: AsyncRead + Unpin>(input: &mut R, cx: &mut Context<'_>, buf: &mut [u8])
+                                                                    -> Poll<io::Result<usize>> {
+    let mut buf = ReadBuf::new(buf);
+    ready!(Pin::new(input).poll_read(cx, &mut buf))?;
+    Poll::Ready(Ok(buf.filled().len()))
+}
+
+/// Read the whole header of either kind of stream, so that it can be parsed by `read_symkey`.
+async fn read_header_bytes<R: AsyncRead + Unpin>(input: &mut R) -> Result<Vec<u8>, Error> {
+    // magic, protocol and version
+    let mut header = vec![0u8; 16];
+    input.read_exact(&mut header).await.map_err(Error::in_header)?;
+    let (protocol, _version) = read_preamble(&mut header.as_slice())?;
+    let rest = if &protocol == THRESHOLD {
+        let mut counts = [0u8; 2];
+        input.read_exact(&mut counts).await.map_err(Error::in_header)?;
+        header.extend_from_slice(&counts);
+        // source public key and nonce, then each target public key and sealed share
+        32 + 24 + counts[1] as usize * (32 + SEALED_SHARE)
+    } else {
+        // source public key, target public key and nonce
+        32 + 32 + 24
+    };
+    let start = header.len();
+    header.resize(start + rest, 0);
+    input.read_exact(&mut header[start..]).await.map_err(Error::in_header)?;
+    Ok(header)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncWriteExt;
+    use crate::{encrypt::Encryptor, keystore::MemoryStore, rng::SeededRng};
+    use super::*;
+
+    #[tokio::test]
+    async fn test_async_encrypt_writer_and_decrypt_reader() {
+        let target_skey = key::SecretKey::generate();
+        let target_pkey = target_skey.public_key();
+        let mut store = MemoryStore::new();
+        store.insert(target_skey.clone());
+        let plaintext: Vec<u8> = (0..150_000u32).map(|i| i as u8).collect();
+
+        // written in pieces, the writer's output is the same as Encryptor::encrypt's and
+        // EncryptWriter's
+        let encryptor = || Encryptor::new(target_pkey).rng(SeededRng::new([5u8; 32]));
+        let mut writer = encryptor().async_writer(Vec::new()).unwrap();
+        for piece in plaintext.chunks(1000) {
+            writer.write_all(piece).await.unwrap();
+        }
+        writer.shutdown().await.unwrap();
+        let encrypted = writer.into_inner();
+        let mut expected = Vec::new();
+        encryptor().encrypt(&mut plaintext.as_slice(), &mut expected).unwrap();
+        assert_eq!(encrypted, expected);
+        let mut sync_writer = encryptor().writer(Vec::new()).unwrap();
+        std::io::Write::write_all(&mut sync_writer, &plaintext).unwrap();
+        assert_eq!(sync_writer.finish().unwrap(), expected);
+
+        let mut reader = AsyncDecryptReader::new(encrypted.as_slice(), &store).await.unwrap();
+        let mut decrypted = Vec::new();
+        reader.read_to_end(&mut decrypted).await.unwrap();
+        assert_eq!(decrypted, plaintext);
+        let summary = reader.summary().unwrap();
+        assert_eq!(summary.chunks, 3);
+        assert!(summary.end_marker);
+
+        // each flush ends a chunk, and a truncated stream has no end marker
+        let mut writer = AsyncEncryptWriter::new(Vec::new(), &target_pkey);
+        writer.write_all(b"Mary had ").await.unwrap();
+        writer.flush().await.unwrap();
+        writer.write_all(b"a little lamb").await.unwrap();
+        writer.shutdown().await.unwrap();
+        let mut encrypted = writer.into_inner();
+        let mut reader = AsyncDecryptReader::new(&encrypted[..encrypted.len() - 2], &store)
+            .await.unwrap();
+        let mut decrypted = Vec::new();
+        reader.read_to_end(&mut decrypted).await.unwrap();
+        assert_eq!(decrypted, b"Mary had a little lamb");
+        assert_eq!(reader.summary().unwrap().chunks, 2);
+        assert!(!reader.summary().unwrap().end_marker);
+
+        // a tampered chunk is an error, rather than plaintext
+        let last = encrypted.len() - 3;
+        encrypted[last] ^= 1;
+        let mut reader = AsyncDecryptReader::new(encrypted.as_slice(), &store).await.unwrap();
+        let error = reader.read_to_end(&mut Vec::new()).await.err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(error.to_string(), "bad ciphertext in chunk 1");
+
+        // threshold streams have a longer header
+        let other_skey = key::SecretKey::generate();
+        store.insert(other_skey.clone());
+        let mut encrypted = Vec::new();
+        let target_keys = [target_pkey, other_skey.public_key(),
+                                                        key::SecretKey::generate().public_key()];
+        Encryptor::threshold(&target_keys, 2)
+            .encrypt(&mut &b"Mary had a little lamb"[..], &mut encrypted).unwrap();
+        let mut reader = AsyncDecryptReader::new(encrypted.as_slice(), &store).await.unwrap();
+        let mut decrypted = Vec::new();
+        reader.read_to_end(&mut decrypted).await.unwrap();
+        assert_eq!(decrypted, b"Mary had a little lamb");
+    }
+}
@@ -1,4 +1,5 @@
-use anyhow;
+use zeroize::Zeroizing;
+
 pub const ALPHABET: &[u8] = r"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz".as_bytes();
 
 /// This specialised base62 encoder converts exactly 32 bytes to exactly 43 characters.
@@ -24,7 +25,7 @@ pub fn encode(input: &[u8; 32]) -> String
         }
     }
 
-    for _ in input.into_iter().take_while(|v| **v == 0) {
+    for _ in input.iter().take_while(|v| **v == 0) {
         if index == output.len() {
             panic!("buffer too small");
         }
@@ -82,6 +83,21 @@ pub fn decode(base62: &str) -> anyhow::Result<[u8; 32]> {
     Ok(output)
 }
 
+/// Decode exactly 43 characters, which must be the encoding of what they decode to.  (`decode`
+/// ignores anything after 43 characters, and more than one string of 43 characters decodes to the
+/// same bytes.)
+pub fn decode_exact(base62: &str) -> anyhow::Result<[u8; 32]> {
+    let invalid = || anyhow::anyhow!("expected exactly 43 base62 characters");
+    if base62.len() != 43 || !base62.bytes().all(|c| c.is_ascii_alphanumeric()) {
+        return Err(invalid());
+    }
+    let output = decode(base62).map_err(|_| invalid())?;
+    if *Zeroizing::new(encode(&output)) != base62 {
+        return Err(invalid());
+    }
+    Ok(output)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -113,5 +129,7 @@ mod tests {
         assert_eq!(decode("yhjskwdA6OZ1AL1YmHWZWm8LLG7HjnuCA2j5rOw8Xp1").unwrap(),
             [0xFF; 32]);
         // TODO: test short strings, long strings and invalid characters
+        assert!(decode_exact("yhjskwdA6OZ1AL1YmHWZWm8LLG7HjnuCA2j5rOw8Xp1Z").is_err());
+        assert!(decode_exact("zzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzz").is_err());
     }
 }
\ No newline at end of file
@@ -0,0 +1,221 @@
+//! Public key bundles, so that sources need not trust a bare base62 public key.
+//!
+//! A bundle carries a public key, its label and an expiry time, signed by the key itself as proof
+//! that the target holds its secret key.  An organisation key can certify the bundle by signing it
+//! too, and sources only encrypt to bundles certified by one of their trust roots.
+//!
+//! ```toml
+//! # turnstile public key bundle
+//! version = 1
+//! public_key = "i8q8p2L8gZpZsPD8NRcTiFfQHLfrhoq3IvsaEwWzPJH"
+//! label = "web01 logs"
+//! not_after = "2027-10-01T00:00:00Z"
+//! signature = "9a41...0c07"
+//!
+//! [certification]
+//! org_key = "CMDw3JKTHZNZBEEZOvBvZ4dxbVMYoUHtaeHyw0I5b9G"
+//! signature = "2b7e...d513"
+//! ```
+
+use std::{fs, io::{Read, Write}, time::{SystemTime, UNIX_EPOCH}};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use sodiumoxide::crypto::box_::{PublicKey, SecretKey};
+
+use crate::{base62, common::{format_time, parse_time}, io::{disk_lookup_with, read_label, KeyAccess},
+            key, signature::{self, Signature}};
+
+/// The version of the bundle format written by this program.
+pub const BUNDLE_VERSION: u32 = 1;
+/// Separate the self-signature and the certification from any other use of their keys.
+const BUNDLE_CONTEXT: &[u8] = b"turnstile bundle v1\0";
+const CERTIFY_CONTEXT: &[u8] = b"turnstile certify v1\0";
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Bundle {
+    pub version: u32,
+    pub public_key: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    /// RFC 3339.  Sources refuse the bundle after this time.
+    pub not_after: String,
+    /// hex XEdDSA self-signature, by the public key.
+    pub signature: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub certification: Option<Certification>,
+}
+
+/// An organisation key's signature over a bundle.
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Certification {
+    pub org_key: String,
+    /// hex XEdDSA signature, by the organisation key.
+    pub signature: String,
+}
+
+impl Bundle {
+    /// A bundle, self-signed by `skey`.
+    pub fn new(skey: &SecretKey, label: Option<&str>, not_after: SystemTime)
+                                                                    -> anyhow::Result<Bundle> {
+        let mut bundle = Bundle {
+            version: BUNDLE_VERSION,
+            public_key: base62::encode(&skey.public_key().0),
+            label: label.map(str::to_string),
+            not_after: format_time(not_after),
+            signature: String::new(),
+            certification: None,
+        };
+        bundle.signature = signature::sign(skey, &bundle.signed_bytes(BUNDLE_CONTEXT)?).to_hex();
+        Ok(bundle)
+    }
+
+    pub fn parse(contents: &str) -> anyhow::Result<Bundle> {
+        let bundle: Bundle = toml::from_str(contents)?;
+        if bundle.version != BUNDLE_VERSION {
+            return Err(anyhow::anyhow!("unsupported bundle version {}", bundle.version));
+        }
+        Ok(bundle)
+    }
+
+    pub fn load(path: &str) -> anyhow::Result<Bundle> {
+        let contents = fs::read_to_string(path)
+            .context(format!("unable to read a bundle from '{path}'"))?;
+        Bundle::parse(&contents).context(format!("invalid bundle '{path}'"))
+    }
+
+    pub fn to_toml(&self) -> anyhow::Result<String> {
+        Ok(format!("# turnstile public key bundle\n{}", toml::to_string(self)?))
+    }
+
+    pub fn pkey(&self) -> anyhow::Result<PublicKey> {
+        Ok(self.public_key.parse::<key::PublicKey>()?.into())
+    }
+
+    /// The bytes which are signed: the public key, the expiry time and the label.
+    fn signed_bytes(&self, context: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let mut bytes = context.to_vec();
+        bytes.extend_from_slice(&self.pkey()?.0);
+        bytes.extend_from_slice(&parse_time(&self.not_after)?.duration_since(UNIX_EPOCH)?.as_secs()
+                                                                                .to_be_bytes());
+        let label = self.label.as_deref().unwrap_or_default().as_bytes();
+        bytes.extend_from_slice(&(label.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(label);
+        Ok(bytes)
+    }
+
+    /// Sign the bundle with an organisation key, after checking its self-signature.
+    pub fn certify(&mut self, org_skey: &SecretKey) -> anyhow::Result<()> {
+        self.check_self_signature()?;
+        self.certification = Some(Certification {
+            org_key: base62::encode(&org_skey.public_key().0),
+            signature: signature::sign(org_skey, &self.signed_bytes(CERTIFY_CONTEXT)?).to_hex(),
+        });
+        Ok(())
+    }
+
+    fn check_self_signature(&self) -> anyhow::Result<()> {
+        signature::verify(&self.pkey()?, &self.signed_bytes(BUNDLE_CONTEXT)?,
+                                                        &Signature::from_hex(&self.signature)?)
+            .context(format!("the self-signature of the bundle for {} is invalid",
+                                                                            self.public_key))
+    }
+
+    /// Check the bundle's signatures and expiry, returning its public key if it is certified by
+    /// one of `trust_roots`.
+    pub fn verify(&self, trust_roots: &[PublicKey], now: SystemTime)
+                                                                -> anyhow::Result<PublicKey> {
+        self.check_self_signature()?;
+        if parse_time(&self.not_after)? < now {
+            return Err(anyhow::anyhow!("the bundle for {} expired at {}", self.public_key,
+                                                                                self.not_after));
+        }
+        let uncertified = || anyhow::anyhow!("the bundle for {} is not certified by a trust root",
+                                                                            self.public_key);
+        let certification = self.certification.as_ref().ok_or_else(uncertified)?;
+        let org_pkey: PublicKey = certification.org_key.parse::<key::PublicKey>()?.into();
+        if !trust_roots.contains(&org_pkey) {
+            return Err(uncertified());
+        }
+        signature::verify(&org_pkey, &self.signed_bytes(CERTIFY_CONTEXT)?,
+                                                &Signature::from_hex(&certification.signature)?)
+            .context(format!("the certification of the bundle for {} is invalid",
+                                                                            self.public_key))?;
+        self.pkey()
+    }
+}
+
+/// Write a self-signed bundle for a key in the key directory, with the label from its public key
+/// file.
+pub fn bundle(keydir: &str, b62_pkey: &str, not_after: &str, access: KeyAccess,
+                                                output: &mut dyn Write) -> anyhow::Result<()> {
+    let skey = disk_lookup_with(keydir, &b62_pkey.parse::<key::PublicKey>()?.into(), access)?;
+    let label = read_label(keydir, b62_pkey);
+    let bundle = Bundle::new(&skey, label.as_deref(), parse_time(not_after)?)?;
+    write!(output, "{}", bundle.to_toml()?)?;
+    Ok(())
+}
+
+/// Certify the bundle read from `input` with an organisation key in the key directory.
+pub fn certify(keydir: &str, org_b62_pkey: &str, input: &mut dyn Read, access: KeyAccess,
+                                                output: &mut dyn Write) -> anyhow::Result<()> {
+    let mut contents = String::new();
+    input.read_to_string(&mut contents)?;
+    let mut bundle = Bundle::parse(&contents).context("invalid bundle")?;
+    let org_skey = disk_lookup_with(keydir, &org_b62_pkey.parse::<key::PublicKey>()?.into(),
+                                                                                    access)?;
+    bundle.certify(&org_skey)?;
+    write!(output, "{}", bundle.to_toml()?)?;
+    Ok(())
+}
+
+/// The public keys of bundle files, each of which must be certified by one of `trust_roots`.
+pub fn trusted_keys(paths: &[String], trust_roots: &[String]) -> anyhow::Result<Vec<String>> {
+    if trust_roots.is_empty() {
+        return Err(anyhow::anyhow!("bundles need a trust root, from --trust-root or trust_roots \
+                                                                                in the config"));
+    }
+    let trust_roots = trust_roots.iter()
+        .map(|root| Ok(root.parse::<key::PublicKey>()?.into()))
+        .collect::<anyhow::Result<Vec<PublicKey>>>()?;
+    let now = SystemTime::now();
+    paths.iter()
+        .map(|path| Ok(base62::encode(&Bundle::load(path)?.verify(&trust_roots, now)
+                                        .context(format!("unable to trust '{path}'"))?.0)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use sodiumoxide::crypto::box_;
+    use super::*;
+
+    #[test]
+    fn test_bundle() {
+        let (pkey, skey) = box_::gen_keypair();
+        let (org_pkey, org_skey) = box_::gen_keypair();
+        let now = parse_time("2026-10-19").unwrap();
+        let not_after = now + Duration::from_secs(365 * 86400);
+        let mut bundle = Bundle::new(&skey, Some("web01 logs"), not_after).unwrap();
+        assert!(bundle.verify(&[org_pkey], now).err().unwrap().to_string()
+                                                                .contains("is not certified"));
+
+        bundle.certify(&org_skey).unwrap();
+        let bundle = Bundle::parse(&bundle.to_toml().unwrap()).unwrap();
+        assert_eq!(bundle.verify(&[org_pkey], now).unwrap(), pkey);
+        assert!(bundle.verify(&[box_::gen_keypair().0], now).is_err());
+        assert!(bundle.verify(&[org_pkey], not_after + Duration::from_secs(1)).err().unwrap()
+                                                                .to_string().contains("expired"));
+
+        // the label and expiry are covered by both signatures
+        let mut changed = Bundle::parse(&bundle.to_toml().unwrap()).unwrap();
+        changed.label = Some("web02 logs".to_string());
+        assert!(changed.verify(&[org_pkey], now).is_err());
+        let mut changed = Bundle::parse(&bundle.to_toml().unwrap()).unwrap();
+        changed.not_after = "2030-01-01T00:00:00Z".to_string();
+        assert!(changed.verify(&[org_pkey], now).is_err());
+    }
+}
@@ -1,7 +1,11 @@
+use std::time::SystemTime;
+
 use sodiumoxide::crypto::box_::Nonce;
 
 pub const FADEDBEE: &[u8; 4] = &[0xFA, 0xDE, 0xDB, 0xEE];
 pub const TURNSTILE: &[u8; 9] = b"turnstile";
+/// In place of `TURNSTILE`, for streams which several recipients must cooperate to decrypt.
+pub const THRESHOLD: &[u8; 9] = b"threshold";
 
 /// As we use a u16 for the ciphertext length, this limits the chunk size.
 pub const MAX_CIPHERTEXT_CHUNK: usize = u16::MAX as usize;
@@ -33,3 +37,27 @@ pub fn calculate_chunk_nonce(initial_nonce: &Nonce, chunk_num: u64) -> Nonce {
     }
     chunk_nonce
 }
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+pub fn unhex(hex: &str) -> anyhow::Result<Vec<u8>> {
+    if !hex.is_ascii() || !hex.len().is_multiple_of(2) {
+        return Err(anyhow::anyhow!("invalid hex string"));
+    }
+    (0..hex.len()).step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16)
+            .map_err(|_| anyhow::anyhow!("invalid hex digits at index: {i}")))
+        .collect()
+}
+
+/// Parse an RFC 3339 time, or a date, which means its midnight UTC.
+pub fn parse_time(text: &str) -> anyhow::Result<SystemTime> {
+    let full = if text.len() == 10 { format!("{text}T00:00:00Z") } else { text.to_string() };
+    humantime::parse_rfc3339_weak(&full).map_err(|e| anyhow::anyhow!("invalid time '{text}': {e}"))
+}
+
+pub fn format_time(time: SystemTime) -> String {
+    humantime::format_rfc3339_seconds(time).to_string()
+}
@@ -0,0 +1,71 @@
+//! Site-wide defaults, read from `/etc/turnstile/config.toml` (or `$TURNSTILE_CONFIG`).
+//!
+//! ```toml
+//! key_path = "~/.turnstile:/etc/turnstile"
+//! trust_roots = ["CMDw3JKTHZNZBEEZOvBvZ4dxbVMYoUHtaeHyw0I5b9G"]
+//! revocation_list = "/etc/turnstile/revoked.toml"
+//! ```
+
+use std::fs;
+
+use anyhow::Context;
+use serde::Deserialize;
+
+/// The environment variable naming a config file to read instead of the system-wide one.
+pub const CONFIG_ENV: &str = "TURNSTILE_CONFIG";
+pub const SYSTEM_CONFIG_PATH: &str = "/etc/turnstile/config.toml";
+/// The key directory search path, when neither `--key-directory` nor the config gives one.
+pub const DEFAULT_KEY_PATH: &str = "~/.turnstile";
+/// The revocation list which encryption checks, when neither `--revocation-list` nor the config
+/// gives one.  It need not exist.
+pub const DEFAULT_REVOCATION_LIST: &str = "/etc/turnstile/revoked.toml";
+
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Colon-separated key directories, searched in order.  The first is where keys are written.
+    pub key_path: Option<String>,
+    /// The organisation keys whose certified public key bundles sources may encrypt to.
+    #[serde(default)]
+    pub trust_roots: Vec<String>,
+    /// The revocation list which encryption checks.
+    pub revocation_list: Option<String>,
+}
+
+impl Config {
+    /// Read the config file, if there is one.
+    pub fn load() -> anyhow::Result<Config> {
+        let path = std::env::var(CONFIG_ENV).unwrap_or_else(|_| SYSTEM_CONFIG_PATH.to_string());
+        match fs::read_to_string(&path) {
+            Ok(contents) => Config::parse(&contents).context(format!("invalid config '{path}'")),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Config::default()),
+            Err(e) => Err(e).context(format!("unable to read config '{path}'")),
+        }
+    }
+
+    pub fn parse(contents: &str) -> anyhow::Result<Config> {
+        Ok(toml::from_str(contents)?)
+    }
+}
+
+/// Split a colon-separated search path into key directories, expanding `~`.
+pub fn split_key_path(key_path: &str) -> Vec<String> {
+    key_path.split(':')
+        .filter(|keydir| !keydir.is_empty())
+        .map(|keydir| shellexpand::tilde(keydir).into_owned())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_path() {
+        let config = Config::parse("key_path = \"/home/web/.turnstile::/etc/turnstile\"\n").unwrap();
+        assert_eq!(split_key_path(&config.key_path.unwrap()),
+                                                vec!["/home/web/.turnstile", "/etc/turnstile"]);
+        assert!(config.trust_roots.is_empty());
+        assert!(Config::parse("key_dir = \"/etc/turnstile\"\n").is_err());
+    }
+}
@@ -1,85 +1,249 @@
 use std::{io::{Read, Write}, mem::size_of};
-use sodiumoxide::crypto::box_::{self, PublicKey, Nonce, SecretKey};
+use sodiumoxide::crypto::box_::{self, PublicKey, Nonce, PrecomputedKey};
 
-use crate::io::{disk_lookup};
+use crate::{base62, error::Error, key, keystore::{KeyStore, LayeredStore}, io::KeyAccess,
+            memory::Locked, threshold::Recipients};
 
 use super::common::*;
 
-pub fn decrypt(keydir: &str, input: &mut dyn Read, output: &mut dyn Write) -> anyhow::Result<()> {
-    // disk_lookup needs to be boxed, as it can be replaced with a (boxed) capturing closure
-    _decrypt(keydir, input, Box::new(disk_lookup), output)
+/// Decrypt, finding the precomputed key in `store`.
+pub fn decrypt(store: &dyn KeyStore, input: &mut dyn Read, output: &mut dyn Write)
+                                                                        -> anyhow::Result<()> {
+    Decryptor::new(store).decrypt(input, output)?;
+    Ok(())
 }
 
-/// Inner decryption routine, for repeatable testing.
-pub fn _decrypt(keydir: &str, input: &mut dyn Read,
-        lookup: Box<dyn Fn(&str, &PublicKey) -> anyhow::Result<SecretKey>>,
-        output: &mut dyn Write) -> anyhow::Result<()> {
-    let (source_pkey, target_pkey, initial_nonce) = read_header(input)?;
-
-    let target_skey = lookup(keydir, &target_pkey)?;
+/// Decrypts either kind of stream, finding keys in a store.
+pub struct Decryptor<'a> {
+    store: &'a dyn KeyStore,
+    require_end_marker: bool,
+}
 
-    let symkey = box_::precompute(&source_pkey, &target_skey);
+impl<'a> Decryptor<'a> {
+    pub fn new(store: &'a dyn KeyStore) -> Self {
+        Decryptor { store, require_end_marker: false }
+    }
 
-    let mut len_buf = [0u8; size_of::<u16>()];
-    for chunk_num in 0u64.. {
-        let chunk_nonce = calculate_chunk_nonce(&initial_nonce, chunk_num);
+    /// Fail with `Error::NoEndMarker` if the stream ends without the end marker, rather than
+    /// only reporting it in the `Summary`.  (The plaintext will already have been written.)
+    pub fn require_end_marker(mut self, require: bool) -> Self {
+        self.require_end_marker = require;
+        self
+    }
 
-        // read length of chunk
-        let num_read = input.read(&mut len_buf)?;
-        if num_read < size_of::<u16>() {
-            return Ok(()); // stream has ended
+    pub fn decrypt(&self, input: &mut dyn Read, output: &mut dyn Write)
+                                                                    -> anyhow::Result<Summary> {
+        let (symkey, initial_nonce) = read_symkey(input, self.store)?;
+        let summary = decrypt_chunks(input, &symkey, &initial_nonce, output)?;
+        if self.require_end_marker && !summary.end_marker {
+            return Err(Error::NoEndMarker { chunks: summary.chunks }.into());
         }
+        Ok(summary)
+    }
+}
 
-        let len = u16::from_be_bytes(len_buf);
-        if len == 0u16 {
-            return Ok(());
+/// Report which directory of the search path holds the secret key for a stream, or for each
+/// recipient of a threshold stream, without decrypting it.
+pub fn which_key(keydirs: &[String], input: &mut dyn Read, output: &mut dyn Write)
+                                                                        -> anyhow::Result<()> {
+    let store = LayeredStore::directories(keydirs, KeyAccess::default());
+    let (protocol, _version) = read_preamble(input)?;
+    if &protocol == THRESHOLD {
+        let recipients = Recipients::read(input)?;
+        writeln!(output, "threshold:  {} of {}", recipients.threshold, recipients.entries.len())?;
+        let mut found = 0;
+        let mut last_error = None;
+        for target_pkey in recipients.target_pkeys() {
+            writeln!(output, "target key: {}", base62::encode(&target_pkey.0))?;
+            match store.position(&target_pkey.into()) {
+                Ok(index) => { writeln!(output, "found in:   {}", keydirs[index])?; found += 1 },
+                Err(e) => { writeln!(output, "found in:   -")?; last_error = Some(e) },
+            }
         }
-        if len > MAX_CIPHERTEXT_CHUNK as u16 {
-            return Err(anyhow::anyhow!("chunk size > MAX_CIPHERTEXT_CHUNK"));
+        if found < recipients.threshold {
+            let message = format!("only {found} of the {} recipient keys needed are in {}",
+                                                    recipients.threshold, keydirs.join(":"));
+            return Err(last_error.expect("a recipient key is missing").context(message));
         }
+        return Ok(());
+    }
+
+    let (_source_pkey, target_pkey, _initial_nonce) = read_keys(input)?;
+    let b62_pkey = base62::encode(&target_pkey.0);
+    writeln!(output, "target key: {b62_pkey}")?;
+    match store.position(&target_pkey.into()) {
+        Ok(index) => writeln!(output, "found in:   {}", keydirs[index])?,
+        Err(e) => return Err(e.context(format!("not found in {}", keydirs.join(":")))),
+    }
+    Ok(())
+}
+
+/// How a successfully deciphered stream ended.
+pub struct Summary {
+    /// The number of chunks which were authenticated and deciphered.
+    pub chunks: u64,
+    /// False if the stream ended without the 0x0000 end marker, which may mean truncation.
+    pub end_marker: bool,
+}
 
-        // read chunk
-        let mut buf = vec![0u8; len as usize];
-        input.read_exact(&mut buf)?;
+/// Read the header of either kind of stream, returning its precomputed key, from `store`, and its
+/// initial nonce.
+pub fn read_symkey(input: &mut dyn Read, store: &dyn KeyStore)
+                                            -> anyhow::Result<(Locked<PrecomputedKey>, Nonce)> {
+    let (protocol, _version) = read_preamble(input)?;
+    if &protocol == THRESHOLD {
+        let recipients = Recipients::read(input)?;
+        return Ok((recipients.content_key(store)?, recipients.initial_nonce));
+    }
+
+    let (source_pkey, target_pkey, initial_nonce) = read_keys(input)?;
+    let symkey = store.precompute(&source_pkey.into(), &target_pkey.into())?;
+    Ok((Locked::new(Box::new((&symkey).into())), initial_nonce))
+}
 
-        // decipher
-        let result = box_::open_precomputed(&buf, &chunk_nonce,&symkey);
-        if let Ok(plaintext) = result {
-            output.write_all(&plaintext)?;
-        } else {
-            return Err(anyhow::anyhow!("bad ciphertext"));
+/// Authenticate and decipher the chunks, up to the end marker or the end of the stream.
+pub fn decrypt_chunks(input: &mut dyn Read, symkey: &PrecomputedKey, initial_nonce: &Nonce,
+                                        output: &mut dyn Write) -> anyhow::Result<Summary> {
+    // the buffer is reused for every chunk, and plaintext is deciphered in place
+    let mut buf = Locked::buffer(MAX_CIPHERTEXT_CHUNK);
+
+    for chunk_num in 0u64.. {
+        match read_chunk(input, &mut buf, symkey, initial_nonce, chunk_num)? {
+            Chunk::Plaintext(len) => output.write_all(&buf[BOX_OVERHEAD..BOX_OVERHEAD + len])?,
+            Chunk::End { end_marker } => return Ok(Summary { chunks: chunk_num, end_marker }),
         }
     }
     unreachable!("loop never exits");
 }
 
-pub fn read_header(input: &mut dyn Read) -> anyhow::Result<(PublicKey, PublicKey, Nonce)> {
-    // read and check magic and version
-    let mut buf = [0u8; 16];
-    input.read_exact(&mut buf)?;
-    if &buf[..4] != FADEDBEE {
-        return Err(anyhow::anyhow!("invalid magic"));
+/// What `read_chunk` found.
+pub enum Chunk {
+    /// The length of the plaintext, which follows the tag in the buffer.
+    Plaintext(usize),
+    /// The stream has ended, with or without the end marker.
+    End { end_marker: bool },
+}
+
+/// Read, authenticate and decipher one chunk, in place in `buf`, which must hold
+/// MAX_CIPHERTEXT_CHUNK bytes.
+pub fn read_chunk(input: &mut dyn Read, buf: &mut [u8], symkey: &PrecomputedKey,
+                            initial_nonce: &Nonce, chunk_num: u64) -> Result<Chunk, Error> {
+    // read length of chunk, which cannot exceed MAX_CIPHERTEXT_CHUNK
+    let len = match read_len(input, chunk_num)? {
+        Some(0) => return Ok(Chunk::End { end_marker: true }),
+        Some(len) => len,
+        None => return Ok(Chunk::End { end_marker: false }), // stream has ended
+    };
+
+    // read chunk
+    let chunk = &mut buf[..len as usize];
+    input.read_exact(chunk).map_err(|e| Error::in_chunk(e, chunk_num))?;
+    Ok(Chunk::Plaintext(open_chunk(chunk, symkey, initial_nonce, chunk_num)?))
+}
+
+/// Authenticate and decipher, in place, a chunk's tag and ciphertext, returning the length of the
+/// plaintext, which follows the tag.
+pub fn open_chunk(chunk: &mut [u8], symkey: &PrecomputedKey, initial_nonce: &Nonce,
+                                                        chunk_num: u64) -> Result<usize, Error> {
+    if chunk.len() < BOX_OVERHEAD {
+        return Err(Error::Authentication { chunk: chunk_num });
+    }
+    let chunk_nonce = calculate_chunk_nonce(initial_nonce, chunk_num);
+    let (tag, ciphertext) = chunk.split_at_mut(BOX_OVERHEAD);
+    let tag = box_::Tag::from_slice(tag).expect("BOX_OVERHEAD is the tag size");
+    if box_::open_detached_precomputed(ciphertext, &tag, &chunk_nonce, symkey).is_err() {
+        return Err(Error::Authentication { chunk: chunk_num });
     }
-    if &buf[4..13] != TURNSTILE {
-        return Err(anyhow::anyhow!("invalid protocol"));
+    Ok(ciphertext.len())
+}
+
+/// Read the length of chunk `chunk_num`, returning None if the stream ends cleanly before it.
+pub fn read_len(input: &mut dyn Read, chunk_num: u64) -> Result<Option<u16>, Error> {
+    let mut len_buf = [0u8; size_of::<u16>()];
+    let mut filled = 0;
+    while filled < len_buf.len() {
+        match input.read(&mut len_buf[filled..])? {
+            0 if filled == 0 => return Ok(None),
+            0 => return Err(Error::Truncated { chunk: chunk_num }),
+            n => filled += n,
+        }
+    }
+    Ok(Some(u16::from_be_bytes(len_buf)))
+}
+
+/// Read and check the magic, protocol and version, returning the protocol (`TURNSTILE` or
+/// `THRESHOLD`) and the format version.
+pub fn read_preamble(input: &mut dyn Read) -> Result<([u8; 9], [u8; 3]), Error> {
+    let mut buf = [0u8; 16];
+    input.read_exact(&mut buf).map_err(Error::in_header)?;
+    if &buf[..4] != FADEDBEE || (&buf[4..13] != TURNSTILE && &buf[4..13] != THRESHOLD) {
+        return Err(Error::BadMagic);
     }
     // FIXME: change this for a more sophisticated check, after the first version is released
     if buf[13..15] != version_bytes()[0..2] { // check first two bytes haven't changed
-        return Err(anyhow::anyhow!("invalid version"));
+        return Err(Error::UnsupportedVersion([buf[13], buf[14], buf[15]]));
     }
+    let mut protocol = [0u8; 9];
+    protocol.copy_from_slice(&buf[4..13]);
+    Ok((protocol, [buf[13], buf[14], buf[15]]))
+}
+
+/// What the header of either kind of stream says, which needs no secret key.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Header {
+    pub version: [u8; 3],
+    /// The ephemeral public key.
+    pub source_key: key::PublicKey,
+    /// The single target key, or each recipient of a threshold stream.
+    pub target_keys: Vec<key::PublicKey>,
+    /// How many recipients of a threshold stream must cooperate to decrypt it.
+    pub threshold: Option<u8>,
+    pub initial_nonce: [u8; 24],
+}
+
+impl Header {
+    pub fn version_string(&self) -> String {
+        let [major, minor, patch] = self.version;
+        format!("{major}.{minor}.{patch}")
+    }
+}
 
-    // read keys and intital nonce
+/// Read and check the header of either kind of stream, leaving `input` at the first chunk.
+pub fn read_header(input: &mut dyn Read) -> anyhow::Result<Header> {
+    let (protocol, version) = read_preamble(input)?;
+    if &protocol == THRESHOLD {
+        let recipients = Recipients::read(input)?;
+        return Ok(Header {
+            version,
+            source_key: recipients.source_pkey.into(),
+            target_keys: recipients.target_pkeys().into_iter().map(Into::into).collect(),
+            threshold: Some(recipients.threshold),
+            initial_nonce: recipients.initial_nonce.0,
+        });
+    }
+    let (source_pkey, target_pkey, initial_nonce) = read_keys(input)?;
+    Ok(Header {
+        version,
+        source_key: source_pkey.into(),
+        target_keys: vec![target_pkey.into()],
+        threshold: None,
+        initial_nonce: initial_nonce.0,
+    })
+}
+
+/// Read the rest of a single recipient header, after the preamble.
+pub fn read_keys(input: &mut dyn Read) -> Result<(PublicKey, PublicKey, Nonce), Error> {
     let mut source_pkey_buf = [0u8; 32];
-    input.read_exact(&mut source_pkey_buf)?;
+    input.read_exact(&mut source_pkey_buf).map_err(Error::in_header)?;
     let source_pkey = PublicKey(source_pkey_buf);
 
     let mut target_pkey_buf = [0u8; 32];
-    input.read_exact(&mut target_pkey_buf)?;
+    input.read_exact(&mut target_pkey_buf).map_err(Error::in_header)?;
     let target_pkey = PublicKey(target_pkey_buf);
 
     let mut initial_nonce_buf = [0u8; 24];
-    input.read_exact(&mut initial_nonce_buf)?;
+    input.read_exact(&mut initial_nonce_buf).map_err(Error::in_header)?;
     let initial_nonce = Nonce(initial_nonce_buf);
 
     Ok((source_pkey, target_pkey, initial_nonce))
-}
\ No newline at end of file
+}
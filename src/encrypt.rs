@@ -1,35 +1,105 @@
 use std::io::{Read, Write};
-use sodiumoxide::crypto::box_::{self, PublicKey, Nonce, SecretKey};
+use sodiumoxide::crypto::box_::{self, PublicKey, Nonce, PrecomputedKey, SecretKey};
 
-use super::{base62, common::*};
+use super::{common::*, key, memory::Locked, rng::{self, OsRng, Rng}, stream::EncryptWriter,
+            threshold};
 
 pub fn encrypt(target_public_key: &str, input: &mut dyn Read,
                                                     output: &mut dyn Write) -> anyhow::Result<()> {
-    let target_pkey = PublicKey(base62::decode(target_public_key)?);
-    let (source_pkey, source_skey) = box_::gen_keypair();
-    let initial_nonce = box_::gen_nonce();
-    _encrypt(&target_pkey, &source_pkey, &source_skey, &initial_nonce, input, output)
+    Encryptor::new(target_public_key.parse()?).encrypt(input, output)
 }
 
-/// Inner encryption routine, capable of deterministic (insecure) encryption for repeatable testing.
-pub fn _encrypt(target_pkey: &PublicKey, source_pkey: &PublicKey, source_skey: &SecretKey,
-        initial_nonce: &Nonce, input: &mut dyn Read, output: &mut dyn Write) -> anyhow::Result<()> {
-    let symkey = box_::precompute(&target_pkey, &source_skey);
+/// Encrypts for a single target key, or for any `threshold` of several target keys.
+///
+/// Each stream has a new ephemeral keypair and a random initial nonce, from `OsRng` unless it is
+/// given another `Rng`.  A `SeededRng` makes encryption deterministic (and insecure) for testing.
+pub struct Encryptor {
+    target_keys: Vec<key::PublicKey>,
+    threshold: Option<u8>,
+    rng: Box<dyn Rng + Send>,
+}
+
+impl Encryptor {
+    pub fn new(target_key: key::PublicKey) -> Self {
+        Encryptor { target_keys: vec![target_key], threshold: None, rng: Box::new(OsRng) }
+    }
+
+    /// Encrypt so that the secret keys of any `threshold` of `target_keys` can decrypt.
+    pub fn threshold(target_keys: &[key::PublicKey], threshold: u8) -> Self {
+        Encryptor { target_keys: target_keys.to_vec(), threshold: Some(threshold),
+                                                                        rng: Box::new(OsRng) }
+    }
+
+    /// Draw the ephemeral keys, nonces and content keys of every stream from `rng`.
+    pub fn rng(mut self, rng: impl Rng + Send + 'static) -> Self {
+        self.rng = Box::new(rng);
+        self
+    }
 
-    write_header(source_pkey, target_pkey, initial_nonce, output)?;
+    pub fn encrypt(&mut self, input: &mut dyn Read, output: &mut dyn Write) -> anyhow::Result<()> {
+        let (source_skey, initial_nonce) = self.ephemeral();
+        let target_pkeys: Vec<PublicKey> = self.target_keys.iter().map(|&pkey| pkey.into())
+            .collect();
+        match self.threshold {
+            Some(threshold) => threshold::write_stream(&target_pkeys, threshold, &source_skey,
+                                            &initial_nonce, self.rng.as_mut(), input, output),
+            None => write_stream(&target_pkeys[0], &source_skey, &initial_nonce, input, output),
+        }
+    }
+
+    /// A `Write` adapter which encrypts everything written to it.
+    pub fn writer<W: Write>(&mut self, output: W) -> anyhow::Result<EncryptWriter<W>> {
+        let target_pkey = self.single_target()?;
+        let (source_skey, initial_nonce) = self.ephemeral();
+        Ok(EncryptWriter::with_keys(output, &target_pkey, &source_skey, &initial_nonce)?)
+    }
 
+    /// An `AsyncWrite` adapter which encrypts everything written to it.
+    #[cfg(feature = "tokio")]
+    pub fn async_writer<W: tokio::io::AsyncWrite + Unpin>(&mut self, output: W)
+                                -> anyhow::Result<crate::async_stream::AsyncEncryptWriter<W>> {
+        let target_pkey = self.single_target()?;
+        let (source_skey, initial_nonce) = self.ephemeral();
+        Ok(crate::async_stream::AsyncEncryptWriter::with_keys(output, &target_pkey, &source_skey,
+                                                                                &initial_nonce))
+    }
+
+    /// A new ephemeral secret key and initial nonce.
+    fn ephemeral(&mut self) -> (SecretKey, Nonce) {
+        let source_skey = rng::gen_secret_key(self.rng.as_mut());
+        (source_skey, rng::gen_nonce(self.rng.as_mut()))
+    }
+
+    fn single_target(&self) -> anyhow::Result<PublicKey> {
+        match self.threshold {
+            Some(_) => Err(anyhow::anyhow!("the streaming adapters only encrypt for a single \
+                                                                                    target key")),
+            None => Ok(self.target_keys[0].into()),
+        }
+    }
+}
+
+/// Write a single recipient stream.
+fn write_stream(target_pkey: &PublicKey, source_skey: &SecretKey, initial_nonce: &Nonce,
+                        input: &mut dyn Read, output: &mut dyn Write) -> anyhow::Result<()> {
+    let symkey = Locked::new(Box::new(box_::precompute(target_pkey, source_skey)));
+
+    write_header(&source_skey.public_key(), target_pkey, initial_nonce, output)?;
+    write_chunks(&symkey, initial_nonce, input, output)
+}
+
+/// Encipher the input in chunks, then write the end marker.
+pub fn write_chunks(symkey: &PrecomputedKey, initial_nonce: &Nonce, input: &mut dyn Read,
+                                                    output: &mut dyn Write) -> anyhow::Result<()> {
     assert!(MAX_CIPHERTEXT_CHUNK <= u16::MAX as usize); 
 
-    let mut buf = [0; MAX_PLAINTEXT_CHUNK];
+    let mut buf = Locked::buffer(MAX_PLAINTEXT_CHUNK);
     for chunk_num in 0u64.. {
         match input.read(&mut buf)? {
             0 => break,
             n => {
-                let chunk_nonce = calculate_chunk_nonce(&initial_nonce, chunk_num);
-                let ciphertext = box_::seal_precomputed(&buf[..n], &chunk_nonce, &symkey);
-                assert!(ciphertext.len() <= MAX_CIPHERTEXT_CHUNK); 
-                output.write_all(&(ciphertext.len() as u16).to_be_bytes())?;
-                output.write_all(&ciphertext)?;
+                let chunk_nonce = calculate_chunk_nonce(initial_nonce, chunk_num);
+                write_chunk(&mut buf[..n], &chunk_nonce, symkey, output)?;
             }
             // TODO: should we trap "if e.kind() == ErrorKind::Interrupted" and continue?
         }
@@ -38,6 +108,18 @@ pub fn _encrypt(target_pkey: &PublicKey, source_pkey: &PublicKey, source_skey: &
     Ok(())
 }
 
+/// Encipher a chunk of plaintext in place, then write its length, tag and ciphertext.
+/// (This is the same as writing the output of `box_::seal_precomputed`, without copying the
+/// plaintext out of its locked buffer.)
+pub fn write_chunk(buf: &mut [u8], chunk_nonce: &Nonce, symkey: &PrecomputedKey,
+                                                    output: &mut dyn Write) -> anyhow::Result<()> {
+    assert!(buf.len() + BOX_OVERHEAD <= MAX_CIPHERTEXT_CHUNK);
+    let tag = box_::seal_detached_precomputed(buf, chunk_nonce, symkey);
+    output.write_all(&((buf.len() + BOX_OVERHEAD) as u16).to_be_bytes())?;
+    output.write_all(&tag.0)?;
+    output.write_all(buf)?;
+    Ok(())
+}
 
 pub fn write_header(source_pkey: &PublicKey, target_pkey: &PublicKey, initial_nonce: &Nonce,
     output: &mut dyn Write) -> anyhow::Result<()> {
@@ -53,20 +135,17 @@ pub fn write_header(source_pkey: &PublicKey, target_pkey: &PublicKey, initial_no
 pub fn encrypt_without_end(target_pkey: &PublicKey, _source_pkey: &PublicKey, source_skey: &SecretKey, 
     mut chunk_num: u64, initial_nonce: Nonce, input: &mut dyn Read, output: &mut dyn Write) -> anyhow::Result<u64> {
 
-    let symkey = box_::precompute(&target_pkey, &source_skey);
+    let symkey = Locked::new(Box::new(box_::precompute(target_pkey, source_skey)));
 
     assert!(MAX_CIPHERTEXT_CHUNK <= u16::MAX as usize); 
 
-    let mut buf = [0; MAX_PLAINTEXT_CHUNK];
+    let mut buf = Locked::buffer(MAX_PLAINTEXT_CHUNK);
     loop {
         match input.read(&mut buf)? {
             0 => break,
             n => {
                 let chunk_nonce = calculate_chunk_nonce(&initial_nonce, chunk_num);
-                let ciphertext = box_::seal_precomputed(&buf[..n], &chunk_nonce, &symkey);
-                assert!(ciphertext.len() <= MAX_CIPHERTEXT_CHUNK); 
-                output.write_all(&(ciphertext.len() as u16).to_be_bytes())?;
-                output.write_all(&ciphertext)?;
+                write_chunk(&mut buf[..n], &chunk_nonce, &symkey, output)?;
                 chunk_num += 1;
             }
             // TODO: should we trap "if e.kind() == ErrorKind::Interrupted" and continue?
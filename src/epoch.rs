@@ -0,0 +1,274 @@
+//! Epoch keys, so that old streams can be made unreadable by destroying their epoch's secret key.
+//!
+//! A target publishes a manifest of its epoch public keys, each with the time its epoch starts,
+//! signed by a long-lived root key.  Sources given the root public key and the manifest encrypt to
+//! the current epoch's key, and the target shreds the secret keys of epochs which have ended.
+//!
+//! ```toml
+//! # turnstile epoch manifest
+//! version = 1
+//! root_key = "i8q8p2L8gZpZsPD8NRcTiFfQHLfrhoq3IvsaEwWzPJH"
+//! signature = "5f0c...e10b"
+//!
+//! [[epochs]]
+//! public_key = "nDrWoUaCxx4M6ucSv4aHkz8BDxSBjT366FuxacxR6MJ"
+//! not_before = "2026-10-01T00:00:00Z"
+//! ```
+
+use std::{fs::{self, OpenOptions}, io::Write, time::{SystemTime, UNIX_EPOCH}};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use sodiumoxide::crypto::box_::{self, PublicKey};
+
+use crate::{base62, common::{format_time, parse_time},
+            io::{destroyed_path, disk_lookup_with, key_path, write_key_files, KeyAccess}, key,
+            signature::{self, Signature}};
+
+/// The version of the manifest format written by this program.
+pub const MANIFEST_VERSION: u32 = 1;
+/// Separates manifest signatures from any other use of the root key.
+const MANIFEST_CONTEXT: &[u8] = b"turnstile manifest v1\0";
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Manifest {
+    pub version: u32,
+    /// The base62 public key whose secret key signs the manifest.
+    pub root_key: String,
+    /// hex XEdDSA signature, by the root key, over the root key and the epochs.
+    pub signature: String,
+    #[serde(default)]
+    pub epochs: Vec<Epoch>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Epoch {
+    pub public_key: String,
+    /// RFC 3339.  Each epoch ends when the next one starts.
+    pub not_before: String,
+}
+
+/// Where the manifest signed by a root key is kept, in the key directory.
+pub fn manifest_path(keydir: &str, b62_root: &str) -> String {
+    format!("{keydir}/{b62_root}.manifest")
+}
+
+impl Manifest {
+    pub fn parse(contents: &str) -> anyhow::Result<Manifest> {
+        let manifest: Manifest = toml::from_str(contents)?;
+        if manifest.version != MANIFEST_VERSION {
+            return Err(anyhow::anyhow!("unsupported manifest version {}", manifest.version));
+        }
+        Ok(manifest)
+    }
+
+    pub fn load(path: &str) -> anyhow::Result<Manifest> {
+        let contents = fs::read_to_string(path)
+            .context(format!("unable to read a manifest from '{path}'"))?;
+        Manifest::parse(&contents).context(format!("invalid manifest '{path}'"))
+    }
+
+    pub fn to_toml(&self) -> anyhow::Result<String> {
+        Ok(format!("# turnstile epoch manifest\n{}", toml::to_string(self)?))
+    }
+
+    /// The bytes which are signed: the root key, then each epoch's public key and start time.
+    fn signed_bytes(&self) -> anyhow::Result<Vec<u8>> {
+        let mut bytes = MANIFEST_CONTEXT.to_vec();
+        bytes.extend_from_slice(self.root_key.parse::<key::PublicKey>()?.as_bytes());
+        for epoch in &self.epochs {
+            bytes.extend_from_slice(epoch.public_key.parse::<key::PublicKey>()?.as_bytes());
+            bytes.extend_from_slice(&epoch.start()?.to_be_bytes());
+        }
+        Ok(bytes)
+    }
+
+    /// Check that the manifest was signed by `root_pkey`, and that its epochs are in order.
+    pub fn verify(&self, root_pkey: &PublicKey) -> anyhow::Result<()> {
+        if self.root_key != base62::encode(&root_pkey.0) {
+            return Err(anyhow::anyhow!("the manifest is signed by {}, not {}", self.root_key,
+                                                                    base62::encode(&root_pkey.0)));
+        }
+        signature::verify(root_pkey, &self.signed_bytes()?, &Signature::from_hex(&self.signature)?)
+            .context("the manifest's signature is invalid")?;
+        for pair in self.epochs.windows(2) {
+            if pair[0].start()? >= pair[1].start()? {
+                return Err(anyhow::anyhow!("the manifest's epochs are not in order"));
+            }
+        }
+        Ok(())
+    }
+
+    /// The public key of the epoch which includes `now`.
+    pub fn current(&self, now: SystemTime) -> anyhow::Result<PublicKey> {
+        let now = now.duration_since(UNIX_EPOCH)?.as_secs();
+        let mut current = None;
+        for epoch in &self.epochs {
+            if epoch.start()? <= now {
+                current = Some(epoch);
+            }
+        }
+        let epoch = current.ok_or_else(|| anyhow::anyhow!("no epoch of the manifest has started"))?;
+        Ok(epoch.public_key.parse::<key::PublicKey>()?.into())
+    }
+}
+
+impl Epoch {
+    /// The start time, in seconds since the unix epoch.
+    fn start(&self) -> anyhow::Result<u64> {
+        Ok(parse_time(&self.not_before)?.duration_since(UNIX_EPOCH)?.as_secs())
+    }
+}
+
+/// The current epoch's public key, from a manifest signed by the root key.
+pub fn current_key(manifest_path: &str, b62_root: &str) -> anyhow::Result<String> {
+    let manifest = Manifest::load(manifest_path)?;
+    manifest.verify(&b62_root.parse::<key::PublicKey>()?.into())
+        .context(format!("unable to trust '{manifest_path}'"))?;
+    Ok(base62::encode(&manifest.current(SystemTime::now())?.0))
+}
+
+/// Generate the key for an epoch starting at `not_before`, adding it to the root key's manifest,
+/// which is created if necessary, and signing the manifest again.
+pub fn new_epoch(keydir: &str, b62_root: &str, not_before: &str, access: KeyAccess,
+                                                output: &mut dyn Write) -> anyhow::Result<()> {
+    let root_pkey: PublicKey = b62_root.parse::<key::PublicKey>()?.into();
+    let not_before = format_time(parse_time(not_before)?);
+    let path = manifest_path(keydir, b62_root);
+    let mut manifest = match fs::metadata(&path) {
+        Ok(_) => {
+            let manifest = Manifest::load(&path)?;
+            manifest.verify(&root_pkey).context(format!("unable to trust '{path}'"))?;
+            manifest
+        },
+        Err(_) => Manifest {
+            version: MANIFEST_VERSION, root_key: b62_root.to_string(), signature: String::new(),
+            epochs: Vec::new(),
+        },
+    };
+    let root_skey = disk_lookup_with(keydir, &root_pkey, access)?;
+
+    let (epoch_pkey, epoch_skey) = box_::gen_keypair();
+    let epoch = Epoch { public_key: base62::encode(&epoch_pkey.0), not_before };
+    if let Some(last) = manifest.epochs.last() {
+        if epoch.start()? <= last.start()? {
+            return Err(anyhow::anyhow!("the new epoch must start after {}", last.not_before));
+        }
+    }
+    write_key_files(keydir, &epoch_pkey, &epoch_skey, Some(&format!("epoch from {}",
+                                                                epoch.not_before)), None)?;
+    writeln!(output, "new epoch key {} from {}", epoch.public_key, epoch.not_before)?;
+    manifest.epochs.push(epoch);
+    manifest.signature = signature::sign(&root_skey, &manifest.signed_bytes()?).to_hex();
+
+    // the manifest is public, and replaced atomically
+    let tmp_path = format!("{path}.tmp");
+    fs::write(&tmp_path, manifest.to_toml()?)
+        .and_then(|_| fs::rename(&tmp_path, &path))
+        .context(format!("unable to write '{path}'"))?;
+    writeln!(output, "manifest written into {path}")?;
+    Ok(())
+}
+
+/// Shred the secret keys of every epoch, of the manifests in the key directory, which ended
+/// before `before`.  A `<PUBLIC_KEY>.destroyed` file is left in place of each.  Each manifest must
+/// be signed by its root key, which is never shredded.
+pub fn shred(keydir: &str, before: &str, output: &mut dyn Write) -> anyhow::Result<()> {
+    let before = parse_time(before)?.duration_since(UNIX_EPOCH)?.as_secs();
+    for entry in fs::read_dir(keydir).context(format!("unable to read '{keydir}'"))? {
+        let path = entry?.path();
+        if path.extension().is_none_or(|extension| extension != "manifest") {
+            continue;
+        }
+        let path = path.to_string_lossy();
+        let manifest = Manifest::load(&path)?;
+        if path != manifest_path(keydir, &manifest.root_key) {
+            return Err(anyhow::anyhow!("'{path}' is signed by {}", manifest.root_key));
+        }
+        manifest.verify(&manifest.root_key.parse::<key::PublicKey>()?.into())
+            .context(format!("unable to trust '{path}'"))?;
+        if manifest.epochs.iter().any(|epoch| epoch.public_key == manifest.root_key) {
+            return Err(anyhow::anyhow!("'{path}' has its root key as an epoch key"));
+        }
+        for pair in manifest.epochs.windows(2) {
+            let [epoch, next] = pair else { unreachable!("windows of 2") };
+            let secret_path = key_path(keydir, &epoch.public_key);
+            if next.start()? >= before || fs::metadata(&secret_path).is_err() {
+                continue;
+            }
+            shred_file(&secret_path)?;
+            fs::write(destroyed_path(keydir, &epoch.public_key),
+                                            format!("{}\n", format_time(SystemTime::now())))
+                .context(format!("unable to record that {} was destroyed", epoch.public_key))?;
+            writeln!(output, "destroyed epoch key {}, which ended at {}", epoch.public_key,
+                                                                            next.not_before)?;
+        }
+    }
+    Ok(())
+}
+
+/// Overwrite a file with zeros before deleting it.  (Filesystems which copy on write, and SSDs,
+/// may still keep the old contents elsewhere.)
+fn shred_file(path: &str) -> anyhow::Result<()> {
+    let len = fs::metadata(path)?.len() as usize;
+    let mut file = OpenOptions::new().write(true).open(path)
+        .context(format!("unable to open '{path}' for shredding"))?;
+    file.write_all(&vec![0u8; len])
+        .and_then(|_| file.sync_all())
+        .and_then(|_| fs::remove_file(path))
+        .context(format!("unable to shred '{path}'"))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::time::Duration;
+    use crate::{error::Error, io::write_key_files, keystore::{DirectoryStore, KeyStore}};
+    use super::*;
+
+    #[test]
+    fn test_epochs() {
+        let keydir = tempfile::tempdir().unwrap();
+        let keydir = keydir.path().to_str().unwrap();
+        let (root_pkey, root_skey) = box_::gen_keypair();
+        write_key_files(keydir, &root_pkey, &root_skey, None, None).unwrap();
+        let b62_root = base62::encode(&root_pkey.0);
+        let access = KeyAccess::default();
+        for date in ["2026-07-01", "2026-08-01", "2026-09-01"] {
+            new_epoch(keydir, &b62_root, date, access, &mut Vec::new()).unwrap();
+        }
+        assert!(new_epoch(keydir, &b62_root, "2026-08-15", access, &mut Vec::new()).is_err());
+
+        let mut manifest = Manifest::load(&manifest_path(keydir, &b62_root)).unwrap();
+        manifest.verify(&root_pkey).unwrap();
+        assert!(manifest.verify(&box_::gen_keypair().0).is_err());
+        let now = parse_time("2026-08-20").unwrap();
+        let august = PublicKey(base62::decode(&manifest.epochs[1].public_key).unwrap());
+        assert_eq!(manifest.current(now).unwrap(), august);
+        assert!(manifest.current(now - Duration::from_secs(90 * 86400)).is_err());
+
+        shred(keydir, "2026-09-01", &mut Vec::new()).unwrap();
+        let store = DirectoryStore::new(keydir, access);
+        let july = PublicKey(base62::decode(&manifest.epochs[0].public_key).unwrap());
+        let error = store.find(&july.into()).err().unwrap();
+        assert!(matches!(Error::find(&error),
+                                        Some(Error::KeyDestroyed { at, .. }) if !at.is_empty()));
+        assert!(store.find(&august.into()).is_ok());
+
+        // moving an epoch's start breaks the signature, so shred refuses to end august early
+        manifest.epochs[2].not_before = "2026-08-02T00:00:00Z".to_string();
+        assert!(manifest.verify(&root_pkey).is_err());
+        let path = manifest_path(keydir, &b62_root);
+        fs::write(&path, manifest.to_toml().unwrap()).unwrap();
+        assert!(shred(keydir, "2026-09-01", &mut Vec::new()).is_err());
+        assert!(store.find(&august.into()).is_ok());
+
+        // nor will it shred the root key, even in a signed manifest
+        manifest.epochs[0].public_key = b62_root.clone();
+        manifest.signature = signature::sign(&root_skey, &manifest.signed_bytes().unwrap()).to_hex();
+        fs::write(&path, manifest.to_toml().unwrap()).unwrap();
+        assert!(shred(keydir, "2026-09-01", &mut Vec::new()).is_err());
+        assert!(store.find(&root_pkey.into()).is_ok());
+    }
+}
@@ -0,0 +1,148 @@
+//! The failures which callers may need to tell apart, such as a missing key from a corrupt or
+//! truncated stream.
+//!
+//! Functions which only read the format return `Error`.  Functions returning `anyhow::Result`
+//! carry an `Error` somewhere in their chain where one of these is the cause, which `Error::find`
+//! returns.
+
+use std::{fmt, io};
+
+#[derive(Debug)]
+pub enum Error {
+    /// The input does not start with the magic and protocol of either kind of stream.
+    BadMagic,
+    /// The stream was written by an incompatible version of the format.
+    UnsupportedVersion([u8; 3]),
+    /// A threshold stream's header has a threshold of fewer than 2, or more than its shares.
+    InvalidThreshold { threshold: u8, shares: u8 },
+    /// No key store holds the secret key for this base62 public key.
+    KeyNotFound(String),
+    /// The secret key for this base62 public key was shredded, at this RFC 3339 time.
+    KeyDestroyed { key: String, at: String },
+    /// A chunk, numbered from 0, failed authentication.
+    Authentication { chunk: u64 },
+    /// The share of a threshold stream for this base62 public key failed authentication.
+    BadShare { key: String },
+    /// The stream ends within its header.
+    TruncatedHeader,
+    /// The stream ends within a chunk, numbered from 0.
+    Truncated { chunk: u64 },
+    /// The stream ends after a whole number of chunks, without the end marker.
+    NoEndMarker { chunks: u64 },
+    Io(io::Error),
+}
+
+impl Error {
+    /// The first `Error` in the chain of `error`.
+    pub fn find(error: &anyhow::Error) -> Option<&Error> {
+        error.chain().find_map(|cause| cause.downcast_ref::<Error>())
+    }
+
+    /// An error from reading a header, in which the end of the stream is truncation.
+    pub(crate) fn in_header(e: io::Error) -> Error {
+        match e.kind() {
+            io::ErrorKind::UnexpectedEof => Error::TruncatedHeader,
+            _ => Error::Io(e),
+        }
+    }
+
+    /// An error from reading chunk `chunk`, in which the end of the stream is truncation.
+    pub(crate) fn in_chunk(e: io::Error, chunk: u64) -> Error {
+        match e.kind() {
+            io::ErrorKind::UnexpectedEof => Error::Truncated { chunk },
+            _ => Error::Io(e),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::BadMagic => write!(f, "invalid magic, this is not a turnstile stream"),
+            Error::UnsupportedVersion([major, minor, patch]) =>
+                write!(f, "unsupported format version {major}.{minor}.{patch}"),
+            Error::InvalidThreshold { threshold, shares } =>
+                write!(f, "invalid threshold {threshold} of {shares}"),
+            Error::KeyNotFound(b62_pkey) => write!(f, "no secret key for {b62_pkey}"),
+            Error::KeyDestroyed { key, at } => write!(f, "epoch key {key} was destroyed at {at}"),
+            Error::Authentication { chunk } => write!(f, "bad ciphertext in chunk {chunk}"),
+            Error::BadShare { key } => write!(f, "bad share for {key}"),
+            Error::TruncatedHeader => write!(f, "stream truncated in its header"),
+            Error::Truncated { chunk } => write!(f, "stream truncated in chunk {chunk}"),
+            Error::NoEndMarker { chunks } =>
+                write!(f, "stream truncated after chunk {chunks}: no end marker"),
+            Error::Io(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+/// For the `Read` and `AsyncRead` adapters, whose callers can find the `Error` with
+/// `io::Error::get_ref`.
+impl From<Error> for io::Error {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::Io(e) => e,
+            e => io::Error::new(io::ErrorKind::InvalidData, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{decrypt::Decryptor, encrypt::Encryptor, key::SecretKey, keystore::MemoryStore,
+                stream::DecryptReader};
+    use super::*;
+
+    #[test]
+    fn test_errors() {
+        let target_skey = SecretKey::generate();
+        let mut encrypted = Vec::new();
+        Encryptor::new(target_skey.public_key())
+            .encrypt(&mut &b"Mary had a little lamb"[..], &mut encrypted).unwrap();
+        let decrypt = |encrypted: &[u8], store: &MemoryStore| {
+            let error = Decryptor::new(store).decrypt(&mut &encrypted[..], &mut Vec::new())
+                .err().unwrap();
+            Error::find(&error).map(ToString::to_string)
+        };
+
+        let mut store = MemoryStore::new();
+        assert_eq!(decrypt(&encrypted, &store).unwrap(),
+                                        format!("no secret key for {}", target_skey.public_key()));
+        store.insert(target_skey.clone());
+        assert_eq!(decrypt(&encrypted[..50], &store).unwrap(), "stream truncated in its header");
+        assert_eq!(decrypt(&encrypted[..encrypted.len() - 5], &store).unwrap(),
+                                                                    "stream truncated in chunk 0");
+        let mut tampered = encrypted.clone();
+        tampered[0] ^= 1;
+        assert!(matches!(decrypt(&tampered, &store), Some(message) if message.contains("magic")));
+        tampered = encrypted.clone();
+        tampered[13] ^= 0xff;
+        assert!(decrypt(&tampered, &store).unwrap().starts_with("unsupported format version"));
+
+        // the Read adapter's io errors carry the Error
+        tampered = encrypted.clone();
+        let last = tampered.len() - 3;
+        tampered[last] ^= 1;
+        let mut reader = DecryptReader::new(tampered.as_slice(), &store).unwrap();
+        let error = std::io::Read::read_to_end(&mut reader, &mut Vec::new()).err().unwrap();
+        assert!(matches!(error.get_ref().and_then(|e| e.downcast_ref::<Error>()),
+                                                    Some(Error::Authentication { chunk: 0 })));
+        let error = Error::Io(std::io::Error::other("disk full"));
+        assert_eq!(std::error::Error::source(&error).unwrap().to_string(), "disk full");
+    }
+}
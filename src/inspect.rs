@@ -0,0 +1,136 @@
+use std::io::{self, Read, Write};
+
+use crate::{common::hex, decrypt::{read_header, read_len, Header}, error::Error};
+
+/// Everything that can be learned about an encrypted stream without a secret key.
+pub struct Inspection {
+    pub header: Header,
+    pub chunks: u64,
+    pub ciphertext_bytes: u64,
+    pub end_marker: bool,
+    pub truncated: bool,
+}
+
+pub fn inspect(input: &mut dyn Read, output: &mut dyn Write, json: bool) -> anyhow::Result<()> {
+    let inspection = scan(input)?;
+    if json {
+        writeln!(output, "{}", inspection.to_json())?;
+    } else {
+        inspection.write_text(output)?;
+    }
+    Ok(())
+}
+
+/// Read the header, then walk the chunks without deciphering them.
+pub fn scan(input: &mut dyn Read) -> anyhow::Result<Inspection> {
+    let mut inspection = Inspection {
+        header: read_header(input)?,
+        chunks: 0, ciphertext_bytes: 0, end_marker: false, truncated: false,
+    };
+
+    loop {
+        let len = match read_len(input, inspection.chunks) {
+            Ok(Some(0)) => { inspection.end_marker = true; break },
+            Ok(Some(len)) => len as u64,
+            Ok(None) => break, // stream has ended without an end marker
+            Err(Error::Truncated { .. }) => { inspection.truncated = true; break },
+            Err(e) => return Err(e.into()),
+        };
+        let skipped = io::copy(&mut input.take(len), &mut io::sink())?;
+        if skipped < len {
+            inspection.truncated = true;
+            break;
+        }
+        inspection.chunks += 1;
+        inspection.ciphertext_bytes += len;
+    }
+    Ok(inspection)
+}
+
+impl Inspection {
+    pub fn to_json(&self) -> serde_json::Value {
+        let header = &self.header;
+        let mut json = serde_json::json!({
+            "version": header.version_string(),
+            "source_public_key": header.source_key.to_string(),
+            "initial_nonce": hex(&header.initial_nonce),
+            "chunks": self.chunks,
+            "ciphertext_bytes": self.ciphertext_bytes,
+            "end_marker": self.end_marker,
+            "truncated": self.truncated,
+        });
+        match header.threshold {
+            Some(threshold) => {
+                json["threshold"] = threshold.into();
+                json["target_public_keys"] = header.target_keys.iter()
+                    .map(ToString::to_string).collect();
+            },
+            None => json["target_public_key"] = header.target_keys[0].to_string().into(),
+        }
+        json
+    }
+
+    pub fn write_text(&self, output: &mut dyn Write) -> anyhow::Result<()> {
+        let header = &self.header;
+        writeln!(output, "version:          {}", header.version_string())?;
+        match header.threshold {
+            Some(threshold) => {
+                writeln!(output, "threshold:        {threshold} of {}", header.target_keys.len())?;
+                for (i, pkey) in header.target_keys.iter().enumerate() {
+                    writeln!(output, "{:18}{pkey}", if i == 0 { "target keys:" } else { "" })?;
+                }
+            },
+            None => writeln!(output, "target key:       {}", header.target_keys[0])?,
+        }
+        writeln!(output, "source key:       {} (ephemeral)", header.source_key)?;
+        writeln!(output, "initial nonce:    {}", hex(&header.initial_nonce))?;
+        writeln!(output, "chunks:           {}", self.chunks)?;
+        writeln!(output, "ciphertext bytes: {}", self.ciphertext_bytes)?;
+        writeln!(output, "end marker:       {}", if self.end_marker { "present" } else { "missing" })?;
+        if self.truncated {
+            writeln!(output, "truncated:        yes")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{encrypt::Encryptor, key::SecretKey, rng::{self, Rng, SeededRng}};
+    use super::*;
+
+    #[test]
+    fn test_scan() {
+        let target_pkey = SecretKey::generate().public_key();
+        let mut encrypted = Vec::<u8>::new();
+        Encryptor::new(target_pkey).rng(SeededRng::new([7u8; 32]))
+            .encrypt(&mut b"Mary had a little lamb".as_slice(), &mut encrypted).unwrap();
+
+        // the ephemeral key and initial nonce are the first draws from the rng
+        let mut rng = SeededRng::new([7u8; 32]);
+        let source_skey = SecretKey::from(rng::gen_secret_key(&mut rng));
+        let mut initial_nonce = [0u8; 24];
+        rng.fill(&mut initial_nonce);
+
+        let inspection = scan(&mut encrypted.as_slice()).unwrap();
+        assert_eq!(inspection.header.target_keys, vec![target_pkey]);
+        assert_eq!(inspection.header.threshold, None);
+        assert_eq!(inspection.header.source_key, source_skey.public_key());
+        assert_eq!(inspection.header.initial_nonce, initial_nonce);
+        assert_eq!(inspection.chunks, 1);
+        assert_eq!(inspection.ciphertext_bytes, 22 + 16);
+        assert!(inspection.end_marker);
+        assert!(!inspection.truncated);
+
+        // drop the end marker and half of the only chunk
+        let inspection = scan(&mut &encrypted[..encrypted.len() - 20]).unwrap();
+        assert_eq!(inspection.chunks, 0);
+        assert!(!inspection.end_marker);
+        assert!(inspection.truncated);
+
+        // and within the length of a chunk
+        let inspection = scan(&mut &encrypted[..104 + 1]).unwrap();
+        assert_eq!(inspection.chunks, 0);
+        assert!(inspection.truncated);
+    }
+}
@@ -1,8 +1,9 @@
-use std::{io::{stdin, stdout, Read, Write}, fs::{File, create_dir_all, self, OpenOptions}};
+use std::{io::{stdin, stdout, Read, Write}, fs::{File, DirBuilder, self, OpenOptions}};
 use anyhow::Context;
 use sodiumoxide::crypto::box_::{PublicKey, SecretKey};
+use zeroize::Zeroizing;
 
-use crate::base62;
+use crate::{base62, error::Error, keyfile::{KeyFile, KEY_FILE_VERSION}, passphrase::Passphrase};
 
 /// Open the program's input file, or stdin if there is no input file.
 /// Note: stdin on Windows only provides utf8.
@@ -31,18 +32,357 @@ pub fn open_output(output: Option<String>) -> anyhow::Result<Box<dyn Write>> {
     }
 }
 
+/// Create the key directory, if necessary, so that only its owner can use it.  An existing key
+/// directory which other users can access, such as one created by an older version, is chmodded.
 pub fn open_or_create_key_directory(path: &str) -> anyhow::Result<()> {
-    create_dir_all(&path)
-        .context(format!("unable to open/create {path}'"))
+    let mut builder = DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+    builder.create(path)
+        .context(format!("unable to open/create {path}'"))?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        let mode = fs::metadata(path).context(format!("unable to open '{path}'"))?
+            .permissions().mode();
+        if mode & 0o077 != 0 {
+            fs::set_permissions(path, fs::Permissions::from_mode(0o700))
+                .context(format!("permissions {:04o} for '{path}' are too open, and it cannot \
+                                    be chmodded to 0700", mode & 0o7777))?;
+        }
+    }
+    Ok(())
 }
 
 pub fn key_path(keydir: &str, b62_pkey: &str) -> String {
     format!("{keydir}/{b62_pkey}.secret") // FIXME: use a Path
 }
 
-/// Read secret key from file.
+/// The companion file holding the public key and its optional label.
+pub fn pub_path(keydir: &str, b62_pkey: &str) -> String {
+    format!("{keydir}/{b62_pkey}.pub")
+}
+
+/// The file left in place of a shredded secret key, holding when it was destroyed.
+pub fn destroyed_path(keydir: &str, b62_pkey: &str) -> String {
+    format!("{keydir}/{b62_pkey}.destroyed")
+}
+
+/// When the secret key for `pkey` was shredded, if it was.
+pub fn read_destroyed(keydir: &str, pkey: &PublicKey) -> Option<String> {
+    let contents = fs::read_to_string(destroyed_path(keydir, &base62::encode(&pkey.0))).ok()?;
+    Some(contents.trim_end().to_string())
+}
+
+/// How secret key files may be used.
+#[derive(Clone, Copy)]
+pub struct KeyAccess {
+    /// Where to get the passphrases of protected secret keys.
+    pub passphrase: Passphrase,
+    /// Use secret key files which other users can read, as ssh does not.
+    pub allow_insecure_permissions: bool,
+}
+
+impl Default for KeyAccess {
+    fn default() -> Self {
+        KeyAccess { passphrase: Passphrase::Prompt, allow_insecure_permissions: false }
+    }
+}
+
+impl KeyAccess {
+    /// Read and check a secret key file, then return its (unlocked) secret key.
+    pub fn open(&self, path: &str) -> anyhow::Result<SecretKey> {
+        read_key_file_with(path, *self)?.open(self.passphrase)
+    }
+}
+
+/// Open a secret file, such as `what` = "a secret key", refusing it if other users can access it
+/// unless `access` allows that.  The permissions checked are those of the file which was opened,
+/// so that it cannot be swapped between the check and the read.
+pub fn open_secret_file(path: &str, what: &str, access: KeyAccess) -> anyhow::Result<File> {
+    let file = File::open(path).context(format!("unable to read {what} from '{path}'"))?;
+    if !access.allow_insecure_permissions {
+        check_file_permissions(&file, path)?;
+    }
+    Ok(file)
+}
+
+/// Refuse secret key files which other users can access.
+pub fn check_permissions(path: &str) -> anyhow::Result<()> {
+    open_secret_file(path, "a secret key", KeyAccess::default()).map(drop)
+}
+
+#[cfg(unix)]
+fn check_file_permissions(file: &File, path: &str) -> anyhow::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mode = file.metadata().context(format!("unable to read '{path}'"))?.permissions().mode();
+    if mode & 0o077 != 0 {
+        return Err(anyhow::anyhow!("permissions {:04o} for '{path}' are too open, secret keys \
+            must only be accessible by their owner (chmod 600 it, or use \
+            --allow-insecure-permissions)", mode & 0o7777));
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn check_file_permissions(_file: &File, _path: &str) -> anyhow::Result<()> {
+    Ok(())
+}
+
+/// Read secret key from file, prompting for its passphrase if it is protected.
 pub fn disk_lookup(keydir: &str, target_pkey: &PublicKey) -> anyhow::Result<SecretKey> {
+    disk_lookup_with(keydir, target_pkey, KeyAccess::default())
+}
+
+/// Read secret key from file, using `access` to unlock it.
+pub fn disk_lookup_with(keydir: &str, target_pkey: &PublicKey, access: KeyAccess)
+                                                                    -> anyhow::Result<SecretKey> {
     let path = key_path(keydir, &base62::encode(&target_pkey.0));
-    let b62_skey = fs::read_to_string(path)?;
-    Ok(SecretKey(base62::decode(&b62_skey)?))
+    if let Some(at) = read_destroyed(keydir, target_pkey) {
+        return Err(Error::KeyDestroyed { key: base62::encode(&target_pkey.0), at }.into());
+    }
+    if fs::metadata(&path).is_err_and(|e| e.kind() == std::io::ErrorKind::NotFound) {
+        return Err(anyhow::Error::new(Error::KeyNotFound(base62::encode(&target_pkey.0)))
+            .context(format!("unable to read a secret key from '{path}'")));
+    }
+    let mut key_file = read_key_file_with(&path, access)?;
+    if key_file.public_key()? != *target_pkey {
+        return Err(anyhow::anyhow!("'{path}' contains the secret key for {}", key_file.public_key));
+    }
+    migrate_key_file(&path, &mut key_file)?;
+    key_file.open(access.passphrase)
+}
+
+/// Rewrite a bare base62 key file in the current format.  Its version is only raised once the
+/// rewrite has succeeded.
+fn migrate_key_file(path: &str, key_file: &mut KeyFile) -> anyhow::Result<()> {
+    let version = key_file.version;
+    if version >= KEY_FILE_VERSION {
+        return Ok(());
+    }
+    key_file.version = KEY_FILE_VERSION;
+    replace_key_file(path, key_file).inspect_err(|_| key_file.version = version)
+        .context(format!("unable to rewrite the bare key file '{path}' in the current format"))
+}
+
+/// Read and check a secret key file, in either the current or the bare base62 format.
+pub fn read_key_file(path: &str) -> anyhow::Result<KeyFile> {
+    let file = File::open(path).context(format!("unable to read a secret key from '{path}'"))?;
+    read_opened_key_file(file, path)
+}
+
+/// As `read_key_file`, checking the permissions of the opened file unless `access` allows others'.
+pub fn read_key_file_with(path: &str, access: KeyAccess) -> anyhow::Result<KeyFile> {
+    read_opened_key_file(open_secret_file(path, "a secret key", access)?, path)
+}
+
+fn read_opened_key_file(mut file: File, path: &str) -> anyhow::Result<KeyFile> {
+    let mut contents = Zeroizing::new(String::new());
+    file.read_to_string(&mut contents)
+        .context(format!("unable to read a secret key from '{path}'"))?;
+    KeyFile::parse(&contents).context(format!("invalid secret key file '{path}'"))
+}
+
+/// A file descriptor inherited from the parent process, such as `--passphrase-fd 3`, which is
+/// checked to be open and then duplicated, so that closing the `File` leaves it open.
+#[cfg(unix)]
+pub fn inherited_fd(fd: i32) -> anyhow::Result<File> {
+    use std::os::fd::BorrowedFd;
+
+    if fd < 0 || unsafe { libc::fcntl(fd, libc::F_GETFD) } == -1 {
+        return Err(anyhow::anyhow!("file descriptor {fd} is not open"));
+    }
+    // SAFETY: the fd is open, and is only borrowed for as long as it takes to duplicate it
+    let borrowed = unsafe { BorrowedFd::borrow_raw(fd) };
+    Ok(File::from(borrowed.try_clone_to_owned()
+        .context(format!("unable to duplicate file descriptor {fd}"))?))
+}
+
+/// Read the label from a public key's companion file, if there is one.
+pub fn read_label(keydir: &str, b62_pkey: &str) -> Option<String> {
+    let contents = fs::read_to_string(pub_path(keydir, b62_pkey)).ok()?;
+    let (_, label) = contents.trim_end().split_once(' ')?;
+    Some(label.to_string())
+}
+
+/// The base62 public keys of all of the secret keys in the key directory, sorted.
+pub fn list_key_names(keydir: &str) -> anyhow::Result<Vec<String>> {
+    let mut names = Vec::new();
+    for entry in fs::read_dir(keydir).context(format!("unable to read '{keydir}'"))? {
+        let file_name = entry?.file_name();
+        if let Some(name) = file_name.to_str().and_then(|name| name.strip_suffix(".secret")) {
+            names.push(name.to_string());
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
+/// Write the secret key, protected by `passphrase` if there is one, and its companion public key
+/// file, returning their paths.  Existing files are never overwritten.
+pub fn write_key_files(keydir: &str, pkey: &PublicKey, skey: &SecretKey, label: Option<&str>,
+                            passphrase: Option<&str>) -> anyhow::Result<(String, String)> {
+    if label.is_some_and(|label| label.contains('\n')) {
+        return Err(anyhow::anyhow!("labels must be a single line"));
+    }
+    // only `keys passwd` takes an empty passphrase, to remove the protection
+    if passphrase == Some("") {
+        return Err(anyhow::anyhow!("the passphrase is empty, which would leave the secret key \
+                                                                                unprotected"));
+    }
+    let b62_pkey = base62::encode(&pkey.0);
+    let path = key_path(keydir, &b62_pkey);
+    let public_path = pub_path(keydir, &b62_pkey);
+    let mut key_file = KeyFile::new(skey, label);
+    if let Some(passphrase) = passphrase {
+        key_file.lock(skey, passphrase)?;
+    }
+
+    // don't leave a secret key without its public key
+    let mut secret_file = create_key_file(&path, 0o600)?;
+    let mut public_file = match create_key_file(&public_path, 0o644) {
+        Ok(file) => file,
+        Err(e) => {
+            fs::remove_file(&path)?;
+            return Err(e);
+        }
+    };
+    secret_file.write_all(Zeroizing::new(key_file.to_toml()?).as_bytes())
+        .context(format!("unable to write a key into '{path}'"))?;
+    write_pub_line(&mut public_file, &b62_pkey, label)
+        .context(format!("unable to write a key into '{public_path}'"))?;
+    Ok((path, public_path))
+}
+
+/// Write only `<PUBLIC_KEY>.pub`, for a secret key which is kept elsewhere.
+pub fn write_pub_file(keydir: &str, pkey: &PublicKey, label: Option<&str>)
+                                                                    -> anyhow::Result<String> {
+    if label.is_some_and(|label| label.contains('\n')) {
+        return Err(anyhow::anyhow!("labels must be a single line"));
+    }
+    let b62_pkey = base62::encode(&pkey.0);
+    let public_path = pub_path(keydir, &b62_pkey);
+    let mut public_file = create_key_file(&public_path, 0o644)?;
+    write_pub_line(&mut public_file, &b62_pkey, label)
+        .context(format!("unable to write a key into '{public_path}'"))?;
+    Ok(public_path)
+}
+
+fn write_pub_line(public_file: &mut File, b62_pkey: &str, label: Option<&str>)
+                                                                    -> std::io::Result<()> {
+    match label {
+        Some(label) => writeln!(public_file, "{b62_pkey} {label}"),
+        None => writeln!(public_file, "{b62_pkey}"),
+    }
+}
+
+/// Rewrite an existing secret key file, replacing it atomically.
+pub fn replace_key_file(path: &str, key_file: &KeyFile) -> anyhow::Result<()> {
+    // a temporary file left by a process which died with this pid is stale
+    let tmp_path = format!("{path}.{}.tmp", std::process::id());
+    let _ = fs::remove_file(&tmp_path);
+    let mut tmp_file = create_key_file(&tmp_path, 0o600)?;
+    tmp_file.write_all(Zeroizing::new(key_file.to_toml()?).as_bytes())
+        .and_then(|_| tmp_file.sync_all())
+        .and_then(|_| fs::rename(&tmp_path, path))
+        .inspect_err(|_| {
+            let _ = fs::remove_file(&tmp_path);
+        })
+        .context(format!("unable to replace '{path}'"))
+}
+
+/// Create a new file for a key, with the given unix permissions.
+pub fn create_key_file(path: &str, _mode: u32) -> anyhow::Result<File> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, _mode);
+    options.open(path)
+        .context(format!("unable to open '{path}' for writing a key"))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+    use sodiumoxide::crypto::box_;
+    use super::*;
+
+    #[test]
+    fn test_key_file_permissions() {
+        let keydir = tempfile::tempdir().unwrap();
+        let keydir = keydir.path().to_str().unwrap();
+        let (pkey, skey) = box_::gen_keypair();
+        let (path, _) = write_key_files(keydir, &pkey, &skey, None, None).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        assert_eq!(disk_lookup(keydir, &pkey).unwrap(), skey);
+
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        assert!(disk_lookup(keydir, &pkey).is_err());
+        let access = KeyAccess { allow_insecure_permissions: true, ..KeyAccess::default() };
+        assert_eq!(disk_lookup_with(keydir, &pkey, access).unwrap(), skey);
+        // an existing key directory which others can access is made private
+        fs::set_permissions(keydir, fs::Permissions::from_mode(0o755)).unwrap();
+        open_or_create_key_directory(keydir).unwrap();
+        assert_eq!(fs::metadata(keydir).unwrap().permissions().mode() & 0o777, 0o700);
+    }
+
+    #[test]
+    fn test_key_file_rewrites() {
+        let keydir = tempfile::tempdir().unwrap();
+        let keydir = keydir.path().to_str().unwrap();
+        let (pkey, skey) = box_::gen_keypair();
+        let path = key_path(keydir, &base62::encode(&pkey.0));
+        create_key_file(&path, 0o600).unwrap().write_all(base62::encode(&skey.0).as_bytes())
+            .unwrap();
+
+        // reading alone does not rewrite it, nor does looking up another key
+        assert_eq!(read_key_file(&path).unwrap().version, 0);
+        let other_pkey = box_::gen_keypair().0;
+        let other_path = key_path(keydir, &base62::encode(&other_pkey.0));
+        fs::copy(&path, &other_path).unwrap();
+        assert!(disk_lookup(keydir, &other_pkey).is_err());
+        assert_eq!(fs::read_to_string(&other_path).unwrap(), base62::encode(&skey.0));
+        fs::remove_file(&other_path).unwrap();
+
+        // a rewrite which fails is reported
+        let mut key_file = read_key_file(&path).unwrap();
+        assert!(migrate_key_file(&format!("{keydir}/missing/key.secret"), &mut key_file).is_err());
+        assert_eq!(key_file.version, 0);
+
+        assert_eq!(disk_lookup(keydir, &pkey).unwrap(), skey);
+        let contents = fs::read_to_string(&path).unwrap();
+        assert!(contents.starts_with("# turnstile secret key\n"));
+        assert_eq!(KeyFile::parse(&contents).unwrap().version, KEY_FILE_VERSION);
+        assert_eq!(disk_lookup(keydir, &pkey).unwrap(), skey);
+
+        // stale temporary files do not stop a rewrite
+        fs::write(format!("{path}.tmp"), "stale").unwrap();
+        fs::write(format!("{path}.{}.tmp", std::process::id()), "stale").unwrap();
+        replace_key_file(&path, &KeyFile::parse(&contents).unwrap()).unwrap();
+
+        // nor can a new key be written with an empty passphrase
+        let (pkey, skey) = box_::gen_keypair();
+        assert!(write_key_files(keydir, &pkey, &skey, None, Some("")).is_err());
+    }
+
+    #[test]
+    fn test_inherited_fd() {
+        use std::os::fd::AsRawFd;
+
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(b"correct horse\n").unwrap();
+        std::io::Seek::rewind(&mut file).unwrap();
+        let mut contents = String::new();
+        inherited_fd(file.as_raw_fd()).unwrap().read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "correct horse\n");
+        // the original is still open
+        assert!(file.metadata().is_ok());
+
+        assert!(inherited_fd(-1).is_err());
+        assert!(inherited_fd(999_999).is_err());
+    }
 }
@@ -0,0 +1,208 @@
+//! Public and secret keys, as the library presents them: 32 bytes, written in base62.
+//!
+//! With `--features serde`, public keys serialize as their base62 strings.  Secret keys do not
+//! serialize, so that they cannot end up in a config or a log by accident.
+
+use std::{fmt, str::FromStr};
+
+use sodiumoxide::crypto::box_;
+use zeroize::Zeroizing;
+
+use crate::base62;
+
+/// An X25519 public key, such as a target key or the ephemeral key of a stream.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct PublicKey([u8; 32]);
+
+/// An X25519 secret key, which is zeroed when dropped.
+#[derive(Clone)]
+pub struct SecretKey(Zeroizing<[u8; 32]>);
+
+/// The key which a target secret key and a stream's source public key share, which is all that
+/// decryption needs.  Stores which never reveal their secret keys return only these.  It is zeroed
+/// when dropped.
+#[derive(Clone)]
+pub struct PrecomputedKey(Zeroizing<[u8; 32]>);
+
+impl PublicKey {
+    pub fn from_bytes(bytes: [u8; 32]) -> Self {
+        PublicKey(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+}
+
+impl SecretKey {
+    /// A new random secret key.
+    pub fn generate() -> Self {
+        box_::gen_keypair().1.into()
+    }
+
+    pub fn from_bytes(bytes: [u8; 32]) -> Self {
+        SecretKey(Zeroizing::new(bytes))
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        box_::SecretKey::from(self).public_key().into()
+    }
+
+    /// The base62 secret key, as in an unprotected secret key file.  There is deliberately no
+    /// `Display`, so that a secret key is only ever written out on purpose.
+    pub fn expose_base62(&self) -> Zeroizing<String> {
+        Zeroizing::new(base62::encode(&self.0))
+    }
+}
+
+impl PrecomputedKey {
+    /// The key shared by `source_key` and `target_key`'s public key.
+    pub fn new(source_key: &PublicKey, target_key: &SecretKey) -> Self {
+        box_::precompute(&(*source_key).into(), &target_key.into()).into()
+    }
+
+    pub fn from_bytes(bytes: [u8; 32]) -> Self {
+        PrecomputedKey(Zeroizing::new(bytes))
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+}
+
+impl fmt::Display for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&base62::encode(&self.0))
+    }
+}
+
+impl fmt::Debug for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PublicKey({self})")
+    }
+}
+
+impl FromStr for PublicKey {
+    type Err = anyhow::Error;
+
+    fn from_str(b62: &str) -> anyhow::Result<Self> {
+        Ok(PublicKey(base62::decode_exact(b62)?))
+    }
+}
+
+/// Only shows the public key.
+impl fmt::Debug for SecretKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SecretKey(for {})", self.public_key())
+    }
+}
+
+/// Shows nothing of the key.
+impl fmt::Debug for PrecomputedKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("PrecomputedKey(..)")
+    }
+}
+
+impl FromStr for SecretKey {
+    type Err = anyhow::Error;
+
+    fn from_str(b62: &str) -> anyhow::Result<Self> {
+        Ok(SecretKey(Zeroizing::new(base62::decode_exact(b62)?)))
+    }
+}
+
+impl From<box_::PublicKey> for PublicKey {
+    fn from(pkey: box_::PublicKey) -> Self {
+        PublicKey(pkey.0)
+    }
+}
+
+impl From<PublicKey> for box_::PublicKey {
+    fn from(pkey: PublicKey) -> Self {
+        box_::PublicKey(pkey.0)
+    }
+}
+
+impl From<box_::SecretKey> for SecretKey {
+    fn from(skey: box_::SecretKey) -> Self {
+        SecretKey(Zeroizing::new(skey.0))
+    }
+}
+
+/// sodiumoxide zeroes its secret keys when they are dropped, too.
+impl From<&SecretKey> for box_::SecretKey {
+    fn from(skey: &SecretKey) -> Self {
+        box_::SecretKey(*skey.0)
+    }
+}
+
+impl From<box_::PrecomputedKey> for PrecomputedKey {
+    fn from(symkey: box_::PrecomputedKey) -> Self {
+        PrecomputedKey(Zeroizing::new(symkey.0))
+    }
+}
+
+impl From<&PrecomputedKey> for box_::PrecomputedKey {
+    fn from(symkey: &PrecomputedKey) -> Self {
+        box_::PrecomputedKey(*symkey.0)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for PublicKey {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for PublicKey {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let b62 = <String as serde::Deserialize>::deserialize(deserializer)?;
+        b62.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keys() {
+        let skey = SecretKey::generate();
+        let pkey = skey.public_key();
+        assert_eq!(pkey.to_string().parse::<PublicKey>().unwrap(), pkey);
+        assert_eq!(skey.expose_base62().parse::<SecretKey>().unwrap().as_bytes(), skey.as_bytes());
+        assert_eq!(format!("{skey:?}"), format!("SecretKey(for {pkey})"));
+        assert!("not base62!".parse::<PublicKey>().is_err());
+
+        // only a key's own 43 characters parse
+        let b62 = pkey.to_string();
+        assert!(format!("{b62}Z").parse::<PublicKey>().is_err());
+        assert!(b62[1..].parse::<PublicKey>().is_err());
+        assert!("z".repeat(43).parse::<PublicKey>().is_err());
+        assert!(format!("{}Z", *skey.expose_base62()).parse::<SecretKey>().is_err());
+
+        let (sodium_pkey, sodium_skey) = box_::gen_keypair();
+        assert_eq!(SecretKey::from(sodium_skey).public_key(), PublicKey::from(sodium_pkey));
+
+        // either side of a stream computes the same key
+        let source_skey = SecretKey::generate();
+        assert_eq!(PrecomputedKey::new(&source_skey.public_key(), &skey).as_bytes(),
+                   PrecomputedKey::new(&pkey, &source_skey).as_bytes());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde() {
+        let pkey = SecretKey::generate().public_key();
+        let json = serde_json::to_string(&pkey).unwrap();
+        assert_eq!(json, format!("\"{pkey}\""));
+        assert_eq!(serde_json::from_str::<PublicKey>(&json).unwrap(), pkey);
+    }
+}
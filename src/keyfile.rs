@@ -0,0 +1,256 @@
+use std::time::SystemTime;
+use serde::{Deserialize, Serialize};
+use sodiumoxide::crypto::{box_::{PublicKey, SecretKey}, hash::sha256, pwhash::argon2id13,
+                                                                                secretbox};
+
+use zeroize::{Zeroize, Zeroizing};
+
+use crate::{base62, common::{hex, unhex}, key, passphrase::Passphrase};
+
+/// The version of the secret key file format written by this program.
+pub const KEY_FILE_VERSION: u32 = 1;
+/// Secret keys are X25519 keys for libsodium's box (X25519, XSalsa20 and Poly1305).
+pub const SUITE: &str = "x25519-xsalsa20poly1305";
+/// Passphrases are stretched with libsodium's Argon2id.
+pub const KDF: &str = "argon2id13";
+/// Passphrase protected secret keys are sealed with libsodium's secretbox.
+pub const AEAD: &str = "xsalsa20poly1305";
+
+/// A secret key file, stored as TOML.
+///
+/// Files from before version 1 contain only the base62 secret key, and are read as a `KeyFile`
+/// without a created date or label.
+#[derive(Serialize, Deserialize)]
+pub struct KeyFile {
+    pub version: u32,
+    pub suite: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    pub public_key: String,
+    /// base62 secret key, absent if the secret key is passphrase protected.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub secret_key: Option<String>,
+    /// base62 SHA-256 of the secret key, to detect corruption before decryption fails.
+    pub checksum: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encrypted_secret_key: Option<EncryptedSecretKey>,
+}
+
+/// A secret key sealed with a key derived from a passphrase.
+#[derive(Serialize, Deserialize)]
+pub struct EncryptedSecretKey {
+    pub kdf: String,
+    pub ops_limit: usize,
+    pub mem_limit: usize,
+    /// hex
+    pub salt: String,
+    pub aead: String,
+    /// hex
+    pub nonce: String,
+    /// hex
+    pub ciphertext: String,
+}
+
+impl KeyFile {
+    pub fn new(skey: &SecretKey, label: Option<&str>) -> KeyFile {
+        KeyFile {
+            version: KEY_FILE_VERSION,
+            suite: SUITE.to_string(),
+            created: Some(humantime::format_rfc3339_seconds(SystemTime::now()).to_string()),
+            label: label.map(str::to_string),
+            public_key: base62::encode(&skey.public_key().0),
+            secret_key: Some(base62::encode(&skey.0)),
+            checksum: checksum(skey),
+            encrypted_secret_key: None,
+        }
+    }
+
+    /// Parse a key file, checking its version, suite, checksum and public key.
+    /// (The checksum and public key of a passphrase protected secret key are checked on unlocking.)
+    pub fn parse(contents: &str) -> anyhow::Result<KeyFile> {
+        if is_legacy(contents) {
+            let skey = SecretKey::from(&contents.trim_end().parse::<key::SecretKey>()?);
+            let mut key_file = KeyFile::new(&skey, None);
+            key_file.version = 0;
+            key_file.created = None;
+            return Ok(key_file);
+        }
+
+        let key_file: KeyFile = toml::from_str(contents)?;
+        if key_file.version != KEY_FILE_VERSION {
+            return Err(anyhow::anyhow!("unsupported key file version {}", key_file.version));
+        }
+        if key_file.suite != SUITE {
+            return Err(anyhow::anyhow!("unsupported key suite '{}'", key_file.suite));
+        }
+        match (&key_file.secret_key, &key_file.encrypted_secret_key) {
+            (Some(b62_skey), None) => {
+                key_file.check(&(&b62_skey.parse::<key::SecretKey>()?).into())?;
+            },
+            (None, Some(encrypted)) => {
+                if encrypted.kdf != KDF || encrypted.aead != AEAD {
+                    return Err(anyhow::anyhow!("unsupported passphrase protection '{}' with '{}'",
+                        encrypted.kdf, encrypted.aead));
+                }
+            },
+            _ => return Err(anyhow::anyhow!("key file must have one of secret_key and \
+                                                                        encrypted_secret_key")),
+        }
+        Ok(key_file)
+    }
+
+    fn check(&self, skey: &SecretKey) -> anyhow::Result<()> {
+        if checksum(skey) != self.checksum {
+            return Err(anyhow::anyhow!("secret key does not match its checksum"));
+        }
+        if base62::encode(&skey.public_key().0) != self.public_key {
+            return Err(anyhow::anyhow!("secret key does not match its public key"));
+        }
+        Ok(())
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.encrypted_secret_key.is_some()
+    }
+
+    /// The secret key, if it is not passphrase protected.
+    pub fn secret_key(&self) -> anyhow::Result<SecretKey> {
+        match &self.secret_key {
+            Some(b62_skey) => Ok((&b62_skey.parse::<key::SecretKey>()?).into()),
+            None => Err(anyhow::anyhow!("the secret key for {} is passphrase protected",
+                                                                            self.public_key)),
+        }
+    }
+
+    /// The secret key, asking for a passphrase if it is passphrase protected.
+    pub fn open(&self, passphrase: Passphrase) -> anyhow::Result<SecretKey> {
+        if !self.is_encrypted() {
+            return self.secret_key();
+        }
+        let name = self.label.as_deref().unwrap_or(&self.public_key);
+        self.unlock(&passphrase.read(&format!("Passphrase for {name}: "))?)
+    }
+
+    pub fn unlock(&self, passphrase: &str) -> anyhow::Result<SecretKey> {
+        let encrypted = match &self.encrypted_secret_key {
+            Some(encrypted) => encrypted,
+            None => return self.secret_key(),
+        };
+        let salt = argon2id13::Salt::from_slice(&unhex(&encrypted.salt)?)
+            .ok_or(anyhow::anyhow!("invalid salt"))?;
+        let nonce = secretbox::Nonce::from_slice(&unhex(&encrypted.nonce)?)
+            .ok_or(anyhow::anyhow!("invalid nonce"))?;
+        let key = derive_key(passphrase, &salt, encrypted.ops_limit, encrypted.mem_limit)?;
+        let plaintext = Zeroizing::new(secretbox::open(&unhex(&encrypted.ciphertext)?, &nonce, &key)
+            .map_err(|_| anyhow::anyhow!("wrong passphrase"))?);
+        let skey = SecretKey::from_slice(&plaintext)
+            .ok_or(anyhow::anyhow!("invalid encrypted secret key"))?;
+        self.check(&skey)?;
+        Ok(skey)
+    }
+
+    /// Protect the secret key with a passphrase, or remove the protection if it is empty.
+    pub fn lock(&mut self, skey: &SecretKey, passphrase: &str) -> anyhow::Result<()> {
+        if passphrase.is_empty() {
+            self.secret_key = Some(base62::encode(&skey.0));
+            self.encrypted_secret_key = None;
+            return Ok(());
+        }
+        let salt = argon2id13::gen_salt();
+        let nonce = secretbox::gen_nonce();
+        let (ops_limit, mem_limit) =
+            (argon2id13::OPSLIMIT_INTERACTIVE.0, argon2id13::MEMLIMIT_INTERACTIVE.0);
+        let key = derive_key(passphrase, &salt, ops_limit, mem_limit)?;
+        self.encrypted_secret_key = Some(EncryptedSecretKey {
+            kdf: KDF.to_string(),
+            ops_limit,
+            mem_limit,
+            salt: hex(&salt.0),
+            aead: AEAD.to_string(),
+            nonce: hex(&nonce.0),
+            ciphertext: hex(&secretbox::seal(&skey.0, &nonce, &key)),
+        });
+        self.secret_key.zeroize();
+        self.secret_key = None;
+        Ok(())
+    }
+
+    pub fn public_key(&self) -> anyhow::Result<PublicKey> {
+        Ok(self.public_key.parse::<key::PublicKey>()?.into())
+    }
+
+    pub fn to_toml(&self) -> anyhow::Result<String> {
+        Ok(format!("# turnstile secret key\n{}", toml::to_string(self)?))
+    }
+}
+
+impl Drop for KeyFile {
+    fn drop(&mut self) {
+        self.secret_key.zeroize();
+    }
+}
+
+fn derive_key(passphrase: &str, salt: &argon2id13::Salt, ops_limit: usize, mem_limit: usize)
+                                                            -> anyhow::Result<secretbox::Key> {
+    let mut key = secretbox::Key([0; secretbox::KEYBYTES]);
+    argon2id13::derive_key(&mut key.0, passphrase.as_bytes(), salt,
+        argon2id13::OpsLimit(ops_limit), argon2id13::MemLimit(mem_limit))
+        .map_err(|_| anyhow::anyhow!("unable to derive a key from the passphrase"))?;
+    Ok(key)
+}
+
+/// Version 0 key files contain nothing but a base62 secret key.
+fn is_legacy(contents: &str) -> bool {
+    !contents.contains('=')
+}
+
+fn checksum(skey: &SecretKey) -> String {
+    base62::encode(&sha256::hash(&skey.0).0)
+}
+
+#[cfg(test)]
+mod tests {
+    use sodiumoxide::crypto::box_;
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let (pkey, skey) = box_::gen_keypair();
+        let contents = KeyFile::new(&skey, Some("web01 \"logs\"")).to_toml().unwrap();
+        let key_file = KeyFile::parse(&contents).unwrap();
+        assert_eq!(key_file.secret_key().unwrap(), skey);
+        assert_eq!(key_file.public_key().unwrap(), pkey);
+        assert_eq!(key_file.label.as_deref(), Some("web01 \"logs\""));
+        assert!(key_file.created.is_some());
+
+        let corrupted = contents.replace(&key_file.checksum, &base62::encode(&[0u8; 32]));
+        assert!(KeyFile::parse(&corrupted).is_err());
+    }
+
+    #[test]
+    fn test_passphrase() {
+        let (_pkey, skey) = box_::gen_keypair();
+        let mut key_file = KeyFile::new(&skey, None);
+        key_file.lock(&skey, "correct horse").unwrap();
+        let contents = key_file.to_toml().unwrap();
+        assert!(!contents.contains(&base62::encode(&skey.0)));
+
+        let key_file = KeyFile::parse(&contents).unwrap();
+        assert!(key_file.is_encrypted());
+        assert!(key_file.secret_key().is_err());
+        assert!(key_file.unlock("battery staple").is_err());
+        assert_eq!(key_file.unlock("correct horse").unwrap(), skey);
+    }
+
+    #[test]
+    fn test_legacy() {
+        let (pkey, skey) = box_::gen_keypair();
+        let key_file = KeyFile::parse(&base62::encode(&skey.0)).unwrap();
+        assert_eq!(key_file.version, 0);
+        assert_eq!(key_file.secret_key().unwrap(), skey);
+        assert_eq!(key_file.public_key().unwrap(), pkey);
+        assert!(key_file.created.is_none());
+    }
+}
@@ -1,18 +1,45 @@
-use std::fs;
+use std::io::Write;
 
-use sodiumoxide::crypto::box_;
-use anyhow::{self, Context};
+use sodiumoxide::crypto::box_::{self, PublicKey, SecretKey};
 
-use crate::io::key_path;
+use crate::{io::write_key_files, passphrase::Passphrase, seed::Seed};
 
 use super::base62;
 
-pub fn keygen(keydir: &str) -> anyhow::Result<()> {
+/// Generate a keypair, protecting the secret key with a passphrase if `passphrase` is given.
+pub fn keygen(keydir: &str, label: Option<&str>, passphrase: Option<Passphrase>,
+                                        output: &mut dyn Write, json: bool) -> anyhow::Result<()> {
     let (target_pkey, target_skey ) = box_::gen_keypair();
+    write_keypair(keydir, &target_pkey, &target_skey, label, passphrase, output, json)
+}
+
+/// Derive the keypair for `path` from a master seed, labelling it with the path by default, so that
+/// it can be regenerated from the seed.
+pub fn keygen_from_seed(keydir: &str, seed: &Seed, path: &str, label: Option<&str>,
+        passphrase: Option<Passphrase>, output: &mut dyn Write, json: bool) -> anyhow::Result<()> {
+    let (target_pkey, target_skey) = seed.derive(path)?;
+    write_keypair(keydir, &target_pkey, &target_skey, label.or(Some(path)), passphrase, output,
+                                                                                            json)
+}
+
+fn write_keypair(keydir: &str, target_pkey: &PublicKey, target_skey: &SecretKey,
+        label: Option<&str>, passphrase: Option<Passphrase>, output: &mut dyn Write, json: bool)
+                                                                        -> anyhow::Result<()> {
+    let passphrase = passphrase.map(|passphrase| passphrase.read_new()).transpose()?;
     let b62_pkey = base62::encode(&target_pkey.0);
-    let b62_skey = base62::encode(&target_skey.0);
-    let path = key_path(keydir, &b62_pkey);
+    let (path, public_path) = write_key_files(keydir, target_pkey, target_skey, label,
+                                                    passphrase.as_ref().map(|p| p.as_str()))?;
 
-    fs::write(&path, b62_skey).context(format!("unable to open '{path}' for writing a key"))?;
+    if json {
+        writeln!(output, "{}", serde_json::json!({
+            "public_key": b62_pkey,
+            "label": label,
+            "secret_key_file": path,
+            "public_key_file": public_path,
+        }))?;
+    } else {
+        writeln!(output, "new secret key written into {path}")?;
+        writeln!(output, "public key: {b62_pkey}")?;
+    }
     Ok(())
-}
\ No newline at end of file
+}
@@ -0,0 +1,250 @@
+use std::{fs, io::{BufRead, BufReader, Read, Write}, path::Path};
+use anyhow::Context;
+use sodiumoxide::crypto::box_::SecretKey;
+use zeroize::Zeroizing;
+
+use crate::{base62, key, keyfile::{KeyFile, KEY_FILE_VERSION}, passphrase::Passphrase,
+    io::{check_permissions, key_path, list_key_names, pub_path, read_key_file, read_label,
+                                                    replace_key_file, write_key_files, KeyAccess}};
+
+/// The first line of an exported key bundle.
+pub const BUNDLE_HEADER: &str = "turnstile-key-bundle 1";
+
+/// What we know about one secret key in the key directory.
+pub struct KeyInfo {
+    pub b62_pkey: String,
+    pub label: Option<String>,
+    pub secret_key_file: String,
+    /// The checked key file, whose public key should match its filename.
+    pub key_file: anyhow::Result<KeyFile>,
+}
+
+impl KeyInfo {
+    pub fn load(keydir: &str, b62_pkey: &str) -> KeyInfo {
+        let secret_key_file = key_path(keydir, b62_pkey);
+        let key_file = read_key_file(&secret_key_file);
+        let label = match &key_file {
+            Ok(KeyFile { label: Some(label), .. }) => Some(label.clone()),
+            _ => read_label(keydir, b62_pkey),
+        };
+        KeyInfo { b62_pkey: b62_pkey.to_string(), label, secret_key_file, key_file }
+    }
+
+    pub fn status(&self) -> String {
+        if let Err(e) = check_permissions(&self.secret_key_file) {
+            return format!("INSECURE, {e}");
+        }
+        match &self.key_file {
+            Ok(key_file) if key_file.public_key != self.b62_pkey =>
+                format!("MISMATCH, secret key is for {}", key_file.public_key),
+            Ok(KeyFile { version: 0, .. }) => "ok (bare key file)".to_string(),
+            Ok(key_file) if key_file.is_encrypted() => "ok (passphrase protected)".to_string(),
+            Ok(_) => "ok".to_string(),
+            Err(e) => format!("UNREADABLE, {e:#}"),
+        }
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        let key_file = self.key_file.as_ref().ok();
+        serde_json::json!({
+            "public_key": self.b62_pkey,
+            "label": self.label,
+            "created": key_file.and_then(|key_file| key_file.created.as_ref()),
+            "secret_key_file": self.secret_key_file,
+            "status": self.status(),
+        })
+    }
+}
+
+/// List every secret key in the directories of the search path, checking each against its
+/// filename.  A directory after the first which cannot be read, such as a missing one, is skipped.
+pub fn list(keydirs: &[String], output: &mut dyn Write, json: bool) -> anyhow::Result<()> {
+    let mut infos = Vec::new();
+    for (i, keydir) in keydirs.iter().enumerate() {
+        let b62_pkeys = match list_key_names(keydir) {
+            Ok(b62_pkeys) => b62_pkeys,
+            Err(_) if i > 0 => continue,
+            Err(e) => return Err(e),
+        };
+        infos.extend(b62_pkeys.iter().map(|b62_pkey| KeyInfo::load(keydir, b62_pkey)));
+    }
+    if json {
+        let infos: Vec<_> = infos.iter().map(KeyInfo::to_json).collect();
+        writeln!(output, "{}", serde_json::Value::Array(infos))?;
+    } else {
+        for info in infos {
+            let label = info.label.as_deref().unwrap_or("");
+            writeln!(output, "{} {} {label}", info.b62_pkey, info.status())?;
+        }
+    }
+    Ok(())
+}
+
+/// Show the first key named `b62_pkey` in the directories of the search path.
+pub fn show(keydirs: &[String], b62_pkey: &str, output: &mut dyn Write, json: bool)
+                                                                        -> anyhow::Result<()> {
+    let b62_pkey = &b62_pkey.parse::<key::PublicKey>()?.to_string();
+    let keydir = keydirs.iter().find(|keydir| Path::new(&key_path(keydir, b62_pkey)).exists())
+        .or(keydirs.first()).ok_or_else(|| anyhow::anyhow!("the key path is empty"))?;
+    let info = KeyInfo::load(keydir, b62_pkey);
+    let key_file = match &info.key_file {
+        Ok(key_file) => key_file,
+        Err(e) => return Err(anyhow::anyhow!("{e:#}")),
+    };
+    if json {
+        writeln!(output, "{}", info.to_json())?;
+    } else {
+        writeln!(output, "public key:      {}", info.b62_pkey)?;
+        writeln!(output, "label:           {}", info.label.as_deref().unwrap_or(""))?;
+        writeln!(output, "created:         {}", key_file.created.as_deref().unwrap_or("unknown"))?;
+        writeln!(output, "secret key file: {}", info.secret_key_file)?;
+        writeln!(output, "status:          {}", info.status())?;
+    }
+    Ok(())
+}
+
+/// Write a bundle of secret keys (all of them, if none are named), for import on another target.
+/// Passphrase protected keys are unlocked, so the bundle must be protected like a secret key.
+pub fn export(keydir: &str, b62_pkeys: &[String], access: KeyAccess, output: &mut dyn Write)
+                                                                        -> anyhow::Result<()> {
+    let b62_pkeys = match b62_pkeys {
+        [] => list_key_names(keydir)?,
+        b62_pkeys => b62_pkeys.iter().map(|b62_pkey| Ok(b62_pkey.parse::<key::PublicKey>()?
+            .to_string())).collect::<anyhow::Result<_>>()?,
+    };
+    writeln!(output, "{BUNDLE_HEADER}")?;
+    for b62_pkey in b62_pkeys {
+        let skey = access.open(&key_path(keydir, &b62_pkey))?;
+        if base62::encode(&skey.public_key().0) != b62_pkey {
+            return Err(anyhow::anyhow!("secret key file for {b62_pkey} contains another key"));
+        }
+        let b62_skey = Zeroizing::new(base62::encode(&skey.0));
+        match KeyInfo::load(keydir, &b62_pkey).label {
+            Some(label) => writeln!(output, "{} {label}", *b62_skey)?,
+            None => writeln!(output, "{}", *b62_skey)?,
+        }
+    }
+    Ok(())
+}
+
+/// Read a bundle written by `export`, adding its keys to the key directory.
+pub fn import(keydir: &str, input: &mut dyn Read, output: &mut dyn Write) -> anyhow::Result<()> {
+    let mut lines = BufReader::new(input).lines();
+    if lines.next().transpose()?.as_deref() != Some(BUNDLE_HEADER) {
+        return Err(anyhow::anyhow!("not a turnstile key bundle"));
+    }
+
+    // check every key before writing any, so that a bad bundle imports nothing
+    let mut keys: Vec<(SecretKey, Option<String>)> = Vec::new();
+    for (i, line) in lines.enumerate() {
+        let line = Zeroizing::new(line?);
+        if line.is_empty() {
+            continue;
+        }
+        let (b62_skey, label) = match line.split_once(' ') {
+            Some((b62_skey, label)) => (b62_skey, Some(label.to_string())),
+            None => (line.as_str(), None),
+        };
+        let skey = SecretKey::from(&b62_skey.parse::<key::SecretKey>()
+            .context(format!("invalid secret key on line {}", i + 2))?);
+        let b62_pkey = base62::encode(&skey.public_key().0);
+        if fs::symlink_metadata(key_path(keydir, &b62_pkey)).is_ok() {
+            return Err(anyhow::anyhow!("{b62_pkey} is already in '{keydir}'"));
+        }
+        if keys.iter().any(|(other, _)| other.public_key() == skey.public_key()) {
+            return Err(anyhow::anyhow!("{b62_pkey} is in the bundle more than once"));
+        }
+        keys.push((skey, label));
+    }
+
+    for (skey, label) in &keys {
+        let pkey = skey.public_key();
+        write_key_files(keydir, &pkey, skey, label.as_deref(), None)?;
+        writeln!(output, "imported {}", base62::encode(&pkey.0))?;
+    }
+    Ok(())
+}
+
+pub fn delete(keydir: &str, b62_pkey: &str, output: &mut dyn Write) -> anyhow::Result<()> {
+    let b62_pkey = &b62_pkey.parse::<key::PublicKey>()?.to_string();
+    let path = key_path(keydir, b62_pkey);
+    fs::remove_file(&path).context(format!("unable to delete '{path}'"))?;
+    let public_path = pub_path(keydir, b62_pkey);
+    if fs::metadata(&public_path).is_ok() {
+        fs::remove_file(&public_path).context(format!("unable to delete '{public_path}'"))?;
+    }
+    writeln!(output, "deleted {path}")?;
+    Ok(())
+}
+
+/// Print the public key belonging to a secret key file.
+pub fn pubkey_of(path: &str, output: &mut dyn Write) -> anyhow::Result<()> {
+    writeln!(output, "{}", read_key_file(path)?.public_key)?;
+    Ok(())
+}
+
+/// Change, add or (with an empty new passphrase) remove the passphrase protecting a secret key.
+pub fn passwd(keydir: &str, b62_pkey: &str, passphrase: Passphrase, output: &mut dyn Write)
+                                                                        -> anyhow::Result<()> {
+    let b62_pkey = &b62_pkey.parse::<key::PublicKey>()?.to_string();
+    let path = key_path(keydir, b62_pkey);
+    let mut key_file = read_key_file(&path)?;
+    let skey = match key_file.is_encrypted() {
+        true => key_file.unlock(&passphrase.read("Current passphrase: ")?)?,
+        false => key_file.secret_key()?,
+    };
+    let new_passphrase = passphrase.read_new()?;
+    key_file.version = KEY_FILE_VERSION; // bare key files are upgraded when rewritten
+    key_file.lock(&skey, &new_passphrase)?;
+    replace_key_file(&path, &key_file)?;
+    match new_passphrase.is_empty() {
+        true => writeln!(output, "passphrase removed from {path}")?,
+        false => writeln!(output, "passphrase changed for {path}")?,
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use sodiumoxide::crypto::box_;
+    use super::*;
+
+    #[test]
+    fn test_export_and_import() {
+        let source_dir = tempfile::tempdir().unwrap();
+        let source_dir = source_dir.path().to_str().unwrap();
+        let target_dir = tempfile::tempdir().unwrap();
+        let target_dir = target_dir.path().to_str().unwrap();
+        let (pkey, skey) = box_::gen_keypair();
+        write_key_files(source_dir, &pkey, &skey, Some("web01 logs"), None).unwrap();
+
+        let mut bundle = Vec::<u8>::new();
+        export(source_dir, &[], KeyAccess::default(), &mut bundle).unwrap();
+        import(target_dir, &mut bundle.as_slice(), &mut std::io::sink()).unwrap();
+
+        let b62_pkey = base62::encode(&pkey.0);
+        assert_eq!(list_key_names(target_dir).unwrap(), vec![b62_pkey.clone()]);
+        let info = KeyInfo::load(target_dir, &b62_pkey);
+        assert_eq!(info.label.as_deref(), Some("web01 logs"));
+        assert_eq!(info.status(), "ok");
+
+        // importing the same key again must not clobber it
+        assert!(import(target_dir, &mut bundle.as_slice(), &mut std::io::sink()).is_err());
+
+        // a bad key later in the bundle imports nothing
+        let other_dir = tempfile::tempdir().unwrap();
+        let other_dir = other_dir.path().to_str().unwrap();
+        bundle.extend_from_slice(b"!nvalid\n");
+        assert!(import(other_dir, &mut bundle.as_slice(), &mut std::io::sink()).is_err());
+        assert!(list_key_names(other_dir).unwrap().is_empty());
+
+        // list and show search every directory of the path, skipping missing ones
+        let keydirs = [other_dir.to_string(), target_dir.to_string(), "/nonexistent".to_string()];
+        let mut listing = Vec::new();
+        list(&keydirs, &mut listing, false).unwrap();
+        assert!(String::from_utf8(listing).unwrap().starts_with(&format!("{b62_pkey} ok ")));
+        let mut shown = Vec::new();
+        show(&keydirs, &b62_pkey, &mut shown, false).unwrap();
+        assert!(String::from_utf8(shown).unwrap().contains(&key_path(target_dir, &b62_pkey)));
+    }
+}
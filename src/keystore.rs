@@ -0,0 +1,270 @@
+//! Where decryption finds target secret keys.
+
+use std::{collections::HashMap, io::Read};
+
+use anyhow::Context;
+use sodiumoxide::crypto::box_;
+use zeroize::Zeroizing;
+
+use crate::{error::Error, key::{PrecomputedKey, PublicKey, SecretKey},
+            io::{disk_lookup_with, open_secret_file, list_key_names, read_destroyed, KeyAccess},
+            keyfile::KeyFile, memory::Locked, passphrase::Passphrase};
+
+/// A source of target secret keys, such as a key directory.
+pub trait KeyStore {
+    /// Find the secret key for `pkey`.
+    fn find(&self, pkey: &PublicKey) -> anyhow::Result<SecretKey>;
+
+    /// The public keys of the secret keys which this store can find.
+    fn candidates(&self) -> anyhow::Result<Vec<PublicKey>>;
+
+    /// The precomputed key for a stream from `source_pkey` to `target_pkey`.
+    ///
+    /// The precomputed key only decrypts streams from that source, so stores which never expose
+    /// their secret keys (such as the agent and PKCS#11 tokens) override this rather than `find`.
+    fn precompute(&self, source_pkey: &PublicKey, target_pkey: &PublicKey)
+                                                            -> anyhow::Result<PrecomputedKey> {
+        Ok(PrecomputedKey::new(source_pkey, &self.find(target_pkey)?))
+    }
+
+    /// When the secret key for `pkey` was shredded, if this store knows that it was.
+    fn destroyed(&self, _pkey: &PublicKey) -> Option<String> {
+        None
+    }
+}
+
+/// Secret key files in a key directory, named `<PUBLIC_KEY>.secret`.
+pub struct DirectoryStore {
+    pub keydir: String,
+    pub access: KeyAccess,
+}
+
+impl DirectoryStore {
+    pub fn new(keydir: &str, access: KeyAccess) -> Self {
+        DirectoryStore { keydir: keydir.to_string(), access }
+    }
+}
+
+impl KeyStore for DirectoryStore {
+    fn find(&self, pkey: &PublicKey) -> anyhow::Result<SecretKey> {
+        Ok(disk_lookup_with(&self.keydir, &(*pkey).into(), self.access)?.into())
+    }
+
+    fn candidates(&self) -> anyhow::Result<Vec<PublicKey>> {
+        // files which are not named after a public key are not candidates
+        Ok(list_key_names(&self.keydir)?.iter()
+            .filter_map(|name| name.parse().ok())
+            .collect())
+    }
+
+    fn destroyed(&self, pkey: &PublicKey) -> Option<String> {
+        read_destroyed(&self.keydir, &(*pkey).into())
+    }
+}
+
+/// Secret keys held in memory, for library users who keep keys elsewhere.
+#[derive(Default)]
+pub struct MemoryStore {
+    keys: HashMap<PublicKey, Locked<box_::SecretKey>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, skey: SecretKey) {
+        self.keys.insert(skey.public_key(), Locked::new(Box::new((&skey).into())));
+    }
+
+    /// Load every secret key that `store` can find.
+    pub fn load(store: &dyn KeyStore) -> anyhow::Result<Self> {
+        let mut memory_store = MemoryStore::new();
+        for pkey in store.candidates()? {
+            memory_store.insert(store.find(&pkey)?);
+        }
+        Ok(memory_store)
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+}
+
+impl KeyStore for MemoryStore {
+    fn find(&self, pkey: &PublicKey) -> anyhow::Result<SecretKey> {
+        self.keys.get(pkey).map(|skey| (**skey).clone().into())
+            .ok_or_else(|| Error::KeyNotFound(pkey.to_string()).into())
+    }
+
+    fn candidates(&self) -> anyhow::Result<Vec<PublicKey>> {
+        let mut pkeys: Vec<PublicKey> = self.keys.keys().copied().collect();
+        pkeys.sort();
+        Ok(pkeys)
+    }
+
+    fn precompute(&self, source_pkey: &PublicKey, target_pkey: &PublicKey)
+                                                            -> anyhow::Result<PrecomputedKey> {
+        // precompute from the locked key, rather than from a copy
+        let target_skey = self.keys.get(target_pkey)
+            .ok_or_else(|| Error::KeyNotFound(target_pkey.to_string()))?;
+        Ok(box_::precompute(&(*source_pkey).into(), target_skey).into())
+    }
+}
+
+/// Several stores searched in order, such as a user's and a system key directory.  The first store
+/// with a candidate for the target key is used.
+pub struct LayeredStore {
+    pub layers: Vec<Box<dyn KeyStore>>,
+}
+
+impl LayeredStore {
+    pub fn new(layers: Vec<Box<dyn KeyStore>>) -> Self {
+        LayeredStore { layers }
+    }
+
+    /// Key directories, searched in order.
+    pub fn directories(keydirs: &[String], access: KeyAccess) -> Self {
+        LayeredStore::new(keydirs.iter()
+            .map(|keydir| Box::new(DirectoryStore::new(keydir, access)) as Box<dyn KeyStore>)
+            .collect())
+    }
+
+    /// The index of the first layer with a candidate for `pkey`.
+    pub fn position(&self, pkey: &PublicKey) -> anyhow::Result<usize> {
+        // an unreadable layer, such as a missing directory, is skipped
+        self.layers.iter()
+            .position(|layer| layer.candidates().is_ok_and(|candidates| candidates.contains(pkey)))
+            .ok_or_else(|| match self.destroyed(pkey) {
+                Some(at) => Error::KeyDestroyed { key: pkey.to_string(), at }.into(),
+                None => Error::KeyNotFound(pkey.to_string()).into(),
+            })
+    }
+
+    /// The first layer with a candidate for `pkey`.
+    pub fn layer_for(&self, pkey: &PublicKey) -> anyhow::Result<&dyn KeyStore> {
+        Ok(self.layers[self.position(pkey)?].as_ref())
+    }
+}
+
+impl KeyStore for LayeredStore {
+    fn find(&self, pkey: &PublicKey) -> anyhow::Result<SecretKey> {
+        self.layer_for(pkey)?.find(pkey)
+    }
+
+    fn candidates(&self) -> anyhow::Result<Vec<PublicKey>> {
+        let mut pkeys = Vec::new();
+        for layer in &self.layers {
+            for pkey in layer.candidates().unwrap_or_default() {
+                if !pkeys.contains(&pkey) {
+                    pkeys.push(pkey);
+                }
+            }
+        }
+        Ok(pkeys)
+    }
+
+    fn precompute(&self, source_pkey: &PublicKey, target_pkey: &PublicKey)
+                                                            -> anyhow::Result<PrecomputedKey> {
+        self.layer_for(target_pkey)?.precompute(source_pkey, target_pkey)
+    }
+
+    fn destroyed(&self, pkey: &PublicKey) -> Option<String> {
+        self.layers.iter().find_map(|layer| layer.destroyed(pkey))
+    }
+}
+
+/// One secret key file's contents, read once from a file descriptor (or any other reader), so that
+/// the key need not be in a key directory.
+pub struct FdStore {
+    key: MemoryStore,
+}
+
+impl FdStore {
+    /// Read a secret key file from an inherited file descriptor, unlocking it with `passphrase`.
+    #[cfg(unix)]
+    pub fn read(fd: i32, passphrase: Passphrase) -> anyhow::Result<Self> {
+        let mut file = crate::io::inherited_fd(fd)?;
+        FdStore::from_reader(&mut file, passphrase)
+            .context(format!("unable to read a secret key from file descriptor {fd}"))
+    }
+
+    #[cfg(not(unix))]
+    pub fn read(_fd: i32, _passphrase: Passphrase) -> anyhow::Result<Self> {
+        Err(anyhow::anyhow!("secret keys can only be read from file descriptors on unix"))
+    }
+
+    /// Read a secret key file which is outside the key directory, such as a mounted secret.
+    pub fn open(path: &str, access: KeyAccess) -> anyhow::Result<Self> {
+        let mut file = open_secret_file(path, "a secret key", access)?;
+        FdStore::from_reader(&mut file, access.passphrase)
+            .context(format!("invalid secret key file '{path}'"))
+    }
+
+    /// Read a secret key file from `input`, unlocking it with `passphrase`.
+    pub fn from_reader(input: &mut dyn Read, passphrase: Passphrase) -> anyhow::Result<Self> {
+        let mut contents = Zeroizing::new(String::new());
+        input.read_to_string(&mut contents)?;
+        let key_file = KeyFile::parse(&contents)?;
+        let mut key = MemoryStore::new();
+        key.insert(key_file.open(passphrase)?.into());
+        Ok(FdStore { key })
+    }
+}
+
+impl KeyStore for FdStore {
+    fn find(&self, pkey: &PublicKey) -> anyhow::Result<SecretKey> {
+        self.key.find(pkey)
+    }
+
+    fn candidates(&self) -> anyhow::Result<Vec<PublicKey>> {
+        self.key.candidates()
+    }
+
+    fn precompute(&self, source_pkey: &PublicKey, target_pkey: &PublicKey)
+                                                            -> anyhow::Result<PrecomputedKey> {
+        self.key.precompute(source_pkey, target_pkey)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::io::write_key_files;
+    use super::*;
+
+    #[test]
+    fn test_layered_store() {
+        let dirs = [tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap()];
+        let keydirs: Vec<String> = dirs.iter()
+            .map(|dir| dir.path().to_str().unwrap().to_string()).collect();
+        let (pkey_a, skey_a) = box_::gen_keypair();
+        let (pkey_b, skey_b) = box_::gen_keypair();
+        write_key_files(&keydirs[0], &pkey_a, &skey_a, None, None).unwrap();
+        write_key_files(&keydirs[1], &pkey_b, &skey_b, None, None).unwrap();
+
+        let store = LayeredStore::directories(&keydirs, KeyAccess::default());
+        assert_eq!(store.find(&pkey_a.into()).unwrap().as_bytes(), &skey_a.0);
+        assert_eq!(store.find(&pkey_b.into()).unwrap().as_bytes(), &skey_b.0);
+        assert_eq!(store.candidates().unwrap().len(), 2);
+        assert_eq!(store.position(&pkey_b.into()).unwrap(), 1);
+        assert!(store.find(&SecretKey::generate().public_key()).is_err());
+
+        let memory_store = MemoryStore::load(&store).unwrap();
+        let (source_pkey, source_skey) = box_::gen_keypair();
+        assert_eq!(memory_store.precompute(&source_pkey.into(), &pkey_b.into()).unwrap().as_bytes(),
+                                                    &box_::precompute(&pkey_b, &source_skey).0);
+    }
+
+    #[test]
+    fn test_fd_store() {
+        let (pkey, skey) = box_::gen_keypair();
+        let contents = KeyFile::new(&skey, None).to_toml().unwrap();
+        let store = FdStore::from_reader(&mut contents.as_bytes(), Passphrase::Prompt).unwrap();
+        assert_eq!(store.candidates().unwrap(), vec![pkey.into()]);
+        assert_eq!(store.find(&pkey.into()).unwrap().as_bytes(), &skey.0);
+    }
+}
@@ -1,38 +1,97 @@
+#[cfg(unix)]
+pub mod agent;
+#[cfg(feature = "tokio")]
+pub mod async_stream;
 pub mod common;
+pub mod config;
 pub mod base62;
+pub mod bundle;
 pub mod decrypt;
 pub mod encrypt;
+pub mod error;
+pub mod epoch;
+pub mod inspect;
 pub mod io;
+pub mod key;
+pub mod keyfile;
 pub mod keygen;
+pub mod keys;
+pub mod keystore;
+pub mod memory;
+pub mod mnemonic;
+pub mod passphrase;
+pub mod revocation;
+pub mod rng;
+#[cfg(feature = "pkcs11")]
+pub mod pkcs11;
+pub mod seed;
+pub mod shamir;
+pub mod selftest;
+pub mod signature;
+pub mod stream;
+pub mod threshold;
+pub mod verify;
+
+pub use decrypt::{Decryptor, Header};
+pub use encrypt::Encryptor;
+pub use error::Error;
+pub use key::{PrecomputedKey, PublicKey, SecretKey};
 
 #[cfg(test)]
 mod tests {
-    use sodiumoxide::crypto::box_::{self, PublicKey, Nonce};
-    use crate::{encrypt, decrypt};
+    use sodiumoxide::crypto::box_;
+    use crate::{keystore::MemoryStore, rng::SeededRng, Decryptor, Encryptor, SecretKey};
 
     #[test]
     fn test_encryption_and_decryption() {
-        let (source_pkey, source_skey) = box_::gen_keypair();
-        let (target_pkey, target_skey) = box_::gen_keypair();
-        let initial_nonce = Nonce([123u8; 24]);
+        let target_skey = SecretKey::generate();
 
         let mut encrypted_file = Vec::<u8>::new();
-        encrypt::_encrypt(&target_pkey, &source_pkey, &source_skey, &initial_nonce,
-            &mut b"Mary had a little lamb".as_slice(), &mut encrypted_file).unwrap();
+        Encryptor::new(target_skey.public_key()).rng(SeededRng::new([123u8; 32]))
+            .encrypt(&mut b"Mary had a little lamb".as_slice(), &mut encrypted_file).unwrap();
 
         assert_eq!(encrypted_file.len(), 104 + 2 + 22 + 16 + 2); 
 
         let mut decrypted_file = Vec::<u8>::new();
-        decrypt::_decrypt("", &mut encrypted_file.as_slice(),
-            // this closure fakes the lookup of the target_skey from the target_pkey
-            Box::new(move |_keydir: &str, pkey: &PublicKey| {
-                assert_eq!(pkey, &target_pkey);
-                Ok(target_skey.clone())
-            }), &mut decrypted_file).unwrap();
+        let mut store = MemoryStore::new();
+        store.insert(target_skey.clone());
+        Decryptor::new(&store).decrypt(&mut encrypted_file.as_slice(), &mut decrypted_file)
+            .unwrap();
 
         assert_eq!(decrypted_file, b"Mary had a little lamb");
     }
 
+    /// Check that corruption and truncation are reported with the chunk number.
+    #[test]
+    fn test_decryption_failures() {
+        let target_skey = SecretKey::generate();
+        let mut encrypted_file = Vec::<u8>::new();
+        Encryptor::new(target_skey.public_key())
+            .encrypt(&mut b"Mary had a little lamb".as_slice(), &mut encrypted_file).unwrap();
+
+        let mut store = MemoryStore::new();
+        store.insert(target_skey.clone());
+        let decrypt = |encrypted: &[u8]| {
+            Decryptor::new(&store).decrypt(&mut &encrypted[..], &mut std::io::sink())
+        };
+
+        let summary = decrypt(&encrypted_file).unwrap();
+        assert_eq!(summary.chunks, 1);
+        assert!(summary.end_marker);
+
+        let summary = decrypt(&encrypted_file[..encrypted_file.len() - 2]).unwrap();
+        assert_eq!(summary.chunks, 1);
+        assert!(!summary.end_marker);
+
+        let error = decrypt(&encrypted_file[..encrypted_file.len() - 10]).err().unwrap();
+        assert_eq!(error.to_string(), "stream truncated in chunk 0");
+
+        let mut corrupted_file = encrypted_file.clone();
+        corrupted_file[110] ^= 1;
+        let error = decrypt(&corrupted_file).err().unwrap();
+        assert_eq!(error.to_string(), "bad ciphertext in chunk 0");
+    }
+
     /// Test that box encryption and decrypton work.
     #[test]
     fn test_box() {
@@ -66,14 +125,14 @@ mod tests {
         let nonce = box_::gen_nonce();
 
         for plaintext in [
-            Vec::from(['a' as u8; 0]),
-            Vec::from(['b' as u8; 1]),
-            Vec::from(['c' as u8; 255]),
-            Vec::from(['d' as u8; 256]),
-            Vec::from(['e' as u8; 257]),
-            Vec::from(['f' as u8; 65535]),
-            Vec::from(['g' as u8; 65536]),
-            Vec::from(['h' as u8; 65537]),
+            Vec::from([b'a'; 0]),
+            Vec::from([b'b'; 1]),
+            Vec::from([b'c'; 255]),
+            Vec::from([b'd'; 256]),
+            Vec::from([b'e'; 257]),
+            Vec::from([b'f'; 65535]),
+            Vec::from([b'g'; 65536]),
+            Vec::from([b'h'; 65537]),
         ] {
             let ciphertext = box_::seal_precomputed(&plaintext, &nonce, &our_precomputed_key);
             println!("plaintext.len(): {}, ciphertext.len(): {}", plaintext.len(), ciphertext.len());
@@ -81,6 +140,21 @@ mod tests {
         }
     }
 
+    /// Check that detached sealing in place produces the tag and ciphertext of combined sealing.
+    #[test]
+    fn test_box_detached() {
+        let (_ourpk, oursk) = box_::gen_keypair();
+        let (theirpk, _theirsk) = box_::gen_keypair();
+        let our_precomputed_key = box_::precompute(&theirpk, &oursk);
+        let nonce = box_::gen_nonce();
+        let plaintext = b"plaintext";
+        let ciphertext = box_::seal_precomputed(plaintext, &nonce, &our_precomputed_key);
+        let mut buf = *plaintext;
+        let tag = box_::seal_detached_precomputed(&mut buf, &nonce, &our_precomputed_key);
+        assert_eq!(&ciphertext[..16], &tag.0);
+        assert_eq!(&ciphertext[16..], &buf);
+    }
+
     /// Check that box decryption detects corruption, rather tha producing corrupted plaintext.
     #[test]
     fn test_box_integrity() {
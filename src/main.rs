@@ -1,58 +1,652 @@
-use clap::{AppSettings, Parser, Subcommand};
-use anyhow;
-use turnstile::{encrypt, decrypt, io, keygen};
+use std::process;
+
+use clap::{AppSettings, ArgGroup, Args, Parser, Subcommand};
+#[cfg(unix)]
+use turnstile::agent;
+#[cfg(feature = "pkcs11")]
+use turnstile::pkcs11;
+use turnstile::{bundle, config::{self, Config}, encrypt, decrypt, epoch, inspect, io, keygen, keys,
+    keystore::{DirectoryStore, FdStore, KeyStore, LayeredStore}, memory, mnemonic,
+    passphrase::Passphrase, revocation::{self, RevocationList}, seed, selftest, shamir, threshold,
+    verify};
 
 #[derive(Parser)]
 #[clap(author, version, about, long_about = None)]
 #[clap(global_setting(AppSettings::PropagateVersion))]
 #[clap(global_setting(AppSettings::UseLongFormatForHelpSubcommand))]
 struct Cli {
-    #[clap(short, long, default_value_t = String::from("~/.turnstile"))]
-    key_directory: String,
+    /// Key directory, or a colon-separated search path whose first directory is where keys are
+    /// written (defaults to key_path in /etc/turnstile/config.toml, or ~/.turnstile)
+    #[clap(short, long, value_name = "KEY_PATH")]
+    key_directory: Option<String>,
     /// Input filename for encryption and decryption, defaults to stdin
     #[clap(short, long)]
     input: Option<String>,
-    /// Output filename for encryption and decryption, defaults to stdout
-    /// or <KEY_DIRECTORY>/.<PUBLIC_KEY>.secret for keygen
+    /// Output filename, defaults to stdout
     #[clap(short, long)]
     output: Option<String>,
+    /// Read secret key passphrases from this file descriptor, one per line, rather than prompting
+    #[clap(long)]
+    passphrase_fd: Option<i32>,
+    /// Use secret key files which other users can read
+    #[clap(long)]
+    allow_insecure_permissions: bool,
+    /// Decrypt using the agent listening on this socket, rather than reading secret keys
+    /// (defaults to $TURNSTILE_AGENT_SOCK).  Repeat it to search several agents in order
+    #[clap(long, multiple_occurrences = true)]
+    agent: Vec<String>,
+    /// Keep target keys on the token of this PKCS#11 module, for keygen and decryption
+    #[cfg(feature = "pkcs11")]
+    #[clap(long, value_name = "MODULE", conflicts_with = "agent")]
+    pkcs11: Option<String>,
     #[clap(subcommand)]
     command: Commands,
 }
 
 #[derive(Subcommand)]
 enum Commands {
-    /// Encrypt with the given public key
+    /// Encrypt with the given public key, or for any THRESHOLD of several public keys
     Encrypt {
-        public_key: String,
+        #[clap(required_unless_present = "bundle")]
+        public_keys: Vec<String>,
+        /// Encrypt with the public key of this bundle, if it is certified by a trust root (repeat
+        /// the option for each bundle)
+        #[clap(long, value_name = "FILE", multiple_occurrences = true, conflicts_with = "manifest")]
+        bundle: Vec<String>,
+        /// Trust bundles certified by this organisation key (defaults to trust_roots in the config)
+        #[clap(long, value_name = "ORG_PUBLIC_KEY", multiple_occurrences = true)]
+        trust_root: Vec<String>,
+        /// Accept bare public keys even though there are trust roots, which otherwise require
+        /// every recipient to come from a certified bundle
+        #[clap(long)]
+        allow_bare_keys: bool,
+        /// How many of the public keys' secret keys must cooperate to decrypt
+        #[clap(long, conflicts_with = "manifest")]
+        threshold: Option<u8>,
+        /// Encrypt with the current epoch's key, from this manifest signed by the public key
+        #[clap(long, value_name = "FILE")]
+        manifest: Option<String>,
+        /// Refuse the public keys revoked in this list (defaults to revocation_list in the config,
+        /// or /etc/turnstile/revoked.toml)
+        #[clap(long, value_name = "FILE")]
+        revocation_list: Option<String>,
     },
     /// Decrypt with a secret key
-    Decrypt,
-    /// Generate a KeyPair
-    Keygen,
+    Decrypt {
+        #[clap(flatten)]
+        secret_key: SecretKeyArgs,
+        /// Only show which directory of the search path holds the secret key, without decrypting
+        #[clap(long)]
+        which_key: bool,
+    },
+    /// Generate a KeyPair, writing <PUBLIC_KEY>.secret and <PUBLIC_KEY>.pub into the key directory
+    Keygen {
+        /// A comment stored alongside the public key
+        #[clap(long)]
+        label: Option<String>,
+        /// Protect the secret key with a passphrase
+        #[clap(long)]
+        passphrase: bool,
+        /// Derive the keypair from the master seed in this file, rather than generating it
+        #[clap(long, value_name = "SEED_FILE", requires = "derivation-path")]
+        from_seed: Option<String>,
+        /// The derivation path of the keypair, such as host/web01/2026-10
+        #[clap(long, value_name = "PATH", requires = "from-seed")]
+        derivation_path: Option<String>,
+        /// Output JSON rather than text
+        #[clap(long)]
+        json: bool,
+    },
+    /// Show the header and chunk structure of an encrypted file, without a secret key
+    Inspect {
+        /// Encrypted file, defaults to the input filename or stdin
+        file: Option<String>,
+        /// Output JSON rather than text
+        #[clap(long)]
+        json: bool,
+    },
+    /// Check that an encrypted file can be decrypted, without writing any plaintext
+    Verify {
+        /// Encrypted file, defaults to the input filename or stdin
+        file: Option<String>,
+        /// Only check the framing, which needs no secret key
+        #[clap(long)]
+        structure: bool,
+        #[clap(flatten)]
+        secret_key: SecretKeyArgs,
+    },
+    /// Check that this build reproduces and decrypts the known-answer test vectors
+    Selftest {
+        /// Check the vectors in this file, rather than the built-in ones
+        #[clap(long, value_name = "FILE")]
+        vectors: Option<String>,
+    },
+    /// Hold the unlocked secret keys, and precompute keys for decryption, until killed
+    #[cfg(unix)]
+    Agent {
+        /// Socket to listen on, defaults to <KEY_DIRECTORY>/agent.sock
+        #[clap(long)]
+        socket: Option<String>,
+    },
+    /// Write a revocation of a key, signed by its secret key, for sources' revocation lists
+    Revoke {
+        public_key: String,
+        /// Why the key was revoked, such as "web01 was compromised"
+        #[clap(long)]
+        reason: Option<String>,
+    },
+    /// Manage the keys in the key directory.  `list` and `show` search the whole key path, but the
+    /// other subcommands only use its first directory
+    Keys {
+        #[clap(subcommand)]
+        command: KeysCommands,
+    },
 }
 
-fn main() -> anyhow::Result<(), anyhow::Error> {
+/// A secret key outside the key directory, such as a secret mounted into a container.  Neither
+/// option puts the key itself on the command line, and only one of them may be given.
+#[derive(Args, Clone, Default)]
+#[clap(group(ArgGroup::new("secret-key-source").args(&["secret-key-fd", "secret-key-file",
+                                                        "share-file"]).multiple(false)))]
+struct SecretKeyArgs {
+    /// Read the secret key file from this file descriptor, rather than the key directory
+    #[clap(long, value_name = "FD")]
+    secret_key_fd: Option<i32>,
+    /// Read this secret key file, rather than the key directory (defaults to
+    /// $TURNSTILE_SECRET_KEY_FILE)
+    #[clap(long, value_name = "PATH")]
+    secret_key_file: Option<String>,
+    /// Rebuild the secret key, only in memory, from the shares in these files (repeat the option
+    /// for each file)
+    #[clap(long, value_name = "PATH", multiple_occurrences = true)]
+    share_file: Vec<String>,
+}
+
+/// The environment variable naming a secret key file to use rather than the key directory.
+const SECRET_KEY_FILE_ENV: &str = "TURNSTILE_SECRET_KEY_FILE";
+
+impl SecretKeyArgs {
+    fn with_env(self) -> Self {
+        SecretKeyArgs {
+            secret_key_file: self.secret_key_file
+                .or_else(|| std::env::var(SECRET_KEY_FILE_ENV).ok()),
+            ..self
+        }
+    }
+
+    fn is_given(&self) -> bool {
+        self.secret_key_fd.is_some() || self.secret_key_file.is_some()
+            || !self.share_file.is_empty()
+    }
+}
+
+#[derive(Subcommand)]
+enum KeysCommands {
+    /// List the secret keys, checking that each matches its filename
+    List {
+        /// Output JSON rather than text
+        #[clap(long)]
+        json: bool,
+    },
+    /// Show the details of one key
+    Show {
+        public_key: String,
+        /// Output JSON rather than text
+        #[clap(long)]
+        json: bool,
+    },
+    /// Write a bundle of secret keys (defaults to all keys), for importing on another target
+    Export {
+        public_keys: Vec<String>,
+    },
+    /// Add the keys from an exported bundle to the key directory
+    Import,
+    /// Delete a secret key and its public key file
+    Delete {
+        public_key: String,
+    },
+    /// Print the public key of a secret key file
+    PubkeyOf {
+        secret_key_file: String,
+    },
+    /// Change the passphrase protecting a secret key (an empty passphrase removes the protection)
+    Passwd {
+        public_key: String,
+    },
+    /// Write a secret key as words for a paper backup
+    Backup {
+        public_key: String,
+        /// Write a 24 word BIP39 mnemonic (currently the only format)
+        #[clap(long)]
+        mnemonic: bool,
+        /// Mask the words with a passphrase extension, which is needed to restore them
+        #[clap(long)]
+        passphrase_extension: bool,
+    },
+    /// Read the words of a paper backup, writing the secret key into the key directory
+    Restore {
+        /// The words were masked with a passphrase extension
+        #[clap(long)]
+        passphrase_extension: bool,
+    },
+    /// Split a secret key into shares, any threshold of which can rebuild it
+    Split {
+        public_key: String,
+        /// The number of shares needed to rebuild the key
+        #[clap(long)]
+        threshold: u8,
+        /// The number of shares to make
+        #[clap(long)]
+        shares: u8,
+        /// Write each share into its own file in this directory, rather than printing them
+        #[clap(long, value_name = "DIR")]
+        out_dir: Option<String>,
+    },
+    /// Rebuild a secret key into the key directory, from shares in files (or one per line of input)
+    Combine {
+        share_files: Vec<String>,
+    },
+    /// Generate a master seed, from which keygen --from-seed derives keypairs
+    NewSeed {
+        seed_file: String,
+    },
+    /// Show the public key derived from the master seed for a derivation path
+    Derive {
+        /// The derivation path, such as host/web01/2026-10
+        derivation_path: String,
+        #[clap(long, value_name = "SEED_FILE")]
+        seed_file: String,
+        /// Output JSON rather than text
+        #[clap(long)]
+        json: bool,
+    },
+    /// Generate the key for a new epoch, adding it to the manifest signed by the root key
+    /// (<ROOT_PUBLIC_KEY>.manifest in the key directory)
+    Epoch {
+        root_public_key: String,
+        /// When the epoch starts, such as 2026-11-01 or 2026-11-01T00:00:00Z
+        #[clap(long, value_name = "TIME")]
+        not_before: String,
+    },
+    /// Overwrite and delete the secret keys of epochs which ended before a time
+    Shred {
+        /// Such as 2026-07-21 or 2026-07-21T00:00:00Z
+        #[clap(long, value_name = "TIME")]
+        before: String,
+    },
+    /// Write a bundle of a public key, its label and an expiry time, signed by its secret key
+    Bundle {
+        public_key: String,
+        /// When sources should stop encrypting to the key, such as 2027-10-01
+        #[clap(long, value_name = "TIME")]
+        not_after: String,
+    },
+    /// Certify the bundle read from the input, by signing it with an organisation key
+    Certify {
+        org_public_key: String,
+    },
+}
+
+/// Exit statuses, so that scripts can tell failures apart.  (clap exits with 2 for usage errors.)
+const EXIT_FAILURE: i32 = 1;
+const EXIT_BAD_MAGIC: i32 = 3;
+const EXIT_UNSUPPORTED_VERSION: i32 = 4;
+const EXIT_KEY_NOT_FOUND: i32 = 5;
+const EXIT_AUTHENTICATION: i32 = 6;
+const EXIT_TRUNCATED: i32 = 7;
+const EXIT_IO: i32 = 8;
+const EXIT_KEY_DESTROYED: i32 = 9;
+const EXIT_INVALID_HEADER: i32 = 10;
+
+fn main() {
+    memory::disable_core_dumps();
     let cli = Cli::parse();
+    if let Err(e) = run(cli) {
+        eprintln!("Error: {e:?}");
+        process::exit(exit_code(&e));
+    }
+}
 
-    let mut boxed_input = io::open_input(cli.input)?;
+fn exit_code(error: &anyhow::Error) -> i32 {
+    match turnstile::Error::find(error) {
+        Some(turnstile::Error::BadMagic) => EXIT_BAD_MAGIC,
+        Some(turnstile::Error::InvalidThreshold { .. }) => EXIT_INVALID_HEADER,
+        Some(turnstile::Error::UnsupportedVersion(_)) => EXIT_UNSUPPORTED_VERSION,
+        Some(turnstile::Error::KeyNotFound(_)) => EXIT_KEY_NOT_FOUND,
+        Some(turnstile::Error::KeyDestroyed { .. }) => EXIT_KEY_DESTROYED,
+        Some(turnstile::Error::Authentication { .. } | turnstile::Error::BadShare { .. }) =>
+            EXIT_AUTHENTICATION,
+        Some(turnstile::Error::TruncatedHeader | turnstile::Error::Truncated { .. } |
+             turnstile::Error::NoEndMarker { .. }) => EXIT_TRUNCATED,
+        Some(turnstile::Error::Io(_)) => EXIT_IO,
+        None if error.chain().any(|cause| cause.is::<std::io::Error>()) => EXIT_IO,
+        None => EXIT_FAILURE,
+    }
+}
+
+fn run(cli: Cli) -> anyhow::Result<()> {
+    let input_name = match &cli.command {
+        Commands::Inspect { file: Some(file), .. } |
+        Commands::Verify { file: Some(file), .. } => Some(file.clone()),
+        _ => cli.input,
+    };
+    let mut boxed_input = io::open_input(input_name)?;
     let input = boxed_input.as_mut();
-    let mut boxed_output = io::open_output(cli.output)?;
+    let mut boxed_output: Box<dyn std::io::Write> = match (&cli.command, cli.output) {
+        // an export holds secret keys, so is created like a key file
+        (Commands::Keys { command: KeysCommands::Export { .. } }, Some(path)) =>
+            Box::new(io::create_key_file(&path, 0o600)?),
+        (_, output) => io::open_output(output)?,
+    };
     let output = boxed_output.as_mut();
 
-    let keydir = shellexpand::tilde(&cli.key_directory);
+    let key_path = match cli.key_directory {
+        Some(key_path) => key_path,
+        None => Config::load()?.key_path.unwrap_or_else(|| config::DEFAULT_KEY_PATH.to_string()),
+    };
+    let keydirs = config::split_key_path(&key_path);
+    // keys are written into, and the agent listens in, the first directory of the search path
+    let keydir = keydirs.first().ok_or_else(|| anyhow::anyhow!("the key path is empty"))?.clone();
+    let passphrase = cli.passphrase_fd.map_or(Passphrase::Prompt, Passphrase::Fd);
+    let access = io::KeyAccess {
+        passphrase,
+        allow_insecure_permissions: cli.allow_insecure_permissions,
+    };
     
-    // encryption does not use the keydir, so it would be odd for it to create it
+    #[cfg(feature = "pkcs11")]
+    let token = cli.pkcs11.map(|module| pkcs11::Token::new(module, passphrase));
+    #[cfg(not(feature = "pkcs11"))]
+    let token = None;
+
+    let secret_key = match &cli.command {
+        Commands::Decrypt { secret_key, .. } | Commands::Verify { secret_key, .. } =>
+            secret_key.clone(),
+        _ => SecretKeyArgs::default(),
+    };
+    // clap keeps the secret key options and --agent and --pkcs11 apart within each level
+    if secret_key.is_given() && (!cli.agent.is_empty() || token.is_some()) {
+        return Err(anyhow::anyhow!("give only one of --secret-key-fd, --secret-key-file, \
+                                    --share-file, --agent and --pkcs11"));
+    }
+    // the environment only chooses where the secret key is when no option does
+    let chosen = secret_key.is_given() || !cli.agent.is_empty() || token.is_some();
+    let secret_key = if chosen { secret_key } else { secret_key.with_env() };
+    #[cfg(unix)]
+    let agent_sockets = match cli.agent {
+        sockets if sockets.is_empty() && !chosen && !secret_key.is_given() =>
+            std::env::var(agent::AGENT_SOCKET_ENV).into_iter().collect(),
+        sockets => sockets,
+    };
+    #[cfg(not(unix))]
+    let agent_sockets = cli.agent;
+
+    // encryption, inspection and the selftest do not use the keydir, so it would be odd for them
+    // to create it
     match cli.command {
-        Commands::Encrypt{public_key: _} => (),
+        Commands::Encrypt { .. } | Commands::Inspect { .. } | Commands::Selftest { .. } => (),
+        Commands::Verify { structure: true, .. } => (),
+        // nor does decryption with a given secret key, or by the agent or the token
+        Commands::Decrypt { .. } | Commands::Verify { .. }
+            if secret_key.is_given() || !agent_sockets.is_empty() || token.is_some() => (),
+        Commands::Decrypt { which_key: true, .. } => (),
         _ => io::open_or_create_key_directory(&keydir)?,
     };
 
     match cli.command {
-        Commands::Encrypt { public_key } => encrypt::encrypt(&public_key, input, output)?,
-        Commands::Decrypt => decrypt::decrypt(&keydir, input, output)?,
-        Commands::Keygen => keygen::keygen(&keydir)?
+        Commands::Encrypt { public_keys, bundle, trust_root, allow_bare_keys, threshold, manifest,
+                            revocation_list } => {
+            let config = Config::load()?;
+            let trust_roots = match trust_root {
+                trust_roots if trust_roots.is_empty() => config.trust_roots,
+                trust_roots => trust_roots,
+            };
+            // a manifest's keys are vouched for by its signature, rather than by a trust root
+            if !trust_roots.is_empty() && !public_keys.is_empty() && manifest.is_none()
+                                                                        && !allow_bare_keys {
+                return Err(anyhow::anyhow!("there are trust roots, so give each recipient as a \
+                                            certified --bundle (or give --allow-bare-keys)"));
+            }
+            let revocations = RevocationList::load(&revocation_list.or(config.revocation_list)
+                .unwrap_or_else(|| config::DEFAULT_REVOCATION_LIST.to_string()))?;
+            let public_keys = match (manifest, &public_keys[..]) {
+                (Some(manifest), [root_public_key]) => {
+                    revocations.check(root_public_key)?;
+                    vec![epoch::current_key(&manifest, root_public_key)?]
+                },
+                (Some(_), _) =>
+                    return Err(anyhow::anyhow!("give only the root key which signs the manifest")),
+                (None, _) => recipients(public_keys, &bundle, &trust_roots)?,
+            };
+            for public_key in &public_keys {
+                revocations.check(public_key)?;
+            }
+            match (threshold, &public_keys[..]) {
+                (Some(threshold), _) => threshold::encrypt(&public_keys, threshold, input, output)?,
+                (None, [public_key]) => encrypt::encrypt(public_key, input, output)?,
+                (None, _) =>
+                    return Err(anyhow::anyhow!("give a --threshold to encrypt for several keys")),
+            }
+        },
+        Commands::Decrypt { which_key: true, .. } => decrypt::which_key(&keydirs, input, output)?,
+        Commands::Decrypt { .. } => decrypt::decrypt(
+            store(&keydirs, secret_key, agent_sockets, token, access)?.as_ref(), input, output)?,
+        // the token generates its own key, which its PIN protects
+        #[cfg(feature = "pkcs11")]
+        Commands::Keygen { passphrase, from_seed, .. }
+            if token.is_some() && (passphrase || from_seed.is_some()) =>
+            return Err(anyhow::anyhow!("--pkcs11 keygen takes neither --passphrase nor \
+                --from-seed, as the token generates the key and its PIN protects it")),
+        #[cfg(feature = "pkcs11")]
+        Commands::Keygen { label, json, .. } if token.is_some() =>
+            pkcs11::keygen(&keydir, &token.unwrap(), label.as_deref(), output, json)?,
+        Commands::Keygen { label, passphrase: protect, from_seed: Some(seed_file),
+                           derivation_path: Some(derivation_path), json } =>
+            keygen::keygen_from_seed(&keydir, &seed::read_seed_file(&seed_file, access)?,
+                &derivation_path, label.as_deref(), protect.then_some(passphrase), output, json)?,
+        Commands::Keygen { label, passphrase: protect, json, .. } => keygen::keygen(&keydir,
+            label.as_deref(), protect.then_some(passphrase), output, json)?,
+        Commands::Inspect { file: _, json } => inspect::inspect(input, output, json)?,
+        Commands::Verify { structure: false, .. } => verify::verify(
+            store(&keydirs, secret_key, agent_sockets, token, access)?.as_ref(), input, output)?,
+        Commands::Verify { structure: true, .. } => verify::verify_structure(input, output)?,
+        Commands::Selftest { vectors } => selftest::selftest(vectors.as_deref(), output)?,
+        #[cfg(unix)]
+        Commands::Agent { socket } => {
+            let socket = socket.unwrap_or_else(|| agent::default_socket_path(&keydir));
+            agent::agent(search_path(&keydirs, access).as_ref(), &socket, output,
+                                                                    &mut std::io::stderr())?
+        },
+        Commands::Revoke { public_key, reason } =>
+            revocation::revoke(&keydir, &public_key, reason.as_deref(), access, output)?,
+        Commands::Keys { command } => match command {
+            KeysCommands::List { json } => keys::list(&keydirs, output, json)?,
+            KeysCommands::Show { public_key, json } =>
+                keys::show(&keydirs, &public_key, output, json)?,
+            KeysCommands::Export { public_keys } =>
+                keys::export(&keydir, &public_keys, access, output)?,
+            KeysCommands::Import => keys::import(&keydir, input, output)?,
+            KeysCommands::Delete { public_key } => keys::delete(&keydir, &public_key, output)?,
+            KeysCommands::PubkeyOf { secret_key_file } => keys::pubkey_of(&secret_key_file, output)?,
+            KeysCommands::Passwd { public_key } =>
+                keys::passwd(&keydir, &public_key, passphrase, output)?,
+            KeysCommands::Backup { mnemonic: false, .. } =>
+                return Err(anyhow::anyhow!("choose a backup format, such as --mnemonic")),
+            KeysCommands::Backup { public_key, mnemonic: true, passphrase_extension } =>
+                mnemonic::backup(&keydir, &public_key, access,
+                                        passphrase_extension.then_some(passphrase), output)?,
+            KeysCommands::Restore { passphrase_extension } => mnemonic::restore(&keydir, input,
+                                        passphrase_extension.then_some(passphrase), output)?,
+            KeysCommands::Split { public_key, threshold, shares, out_dir } =>
+                shamir::split_key(&keydir, &public_key, threshold, shares, out_dir.as_deref(),
+                                                                            access, output)?,
+            KeysCommands::Combine { share_files } if share_files.is_empty() => {
+                let mut text = zeroize::Zeroizing::new(String::new());
+                input.read_to_string(&mut text)?;
+                shamir::combine_key(&keydir, &shamir::parse_shares(&text)?, output)?
+            },
+            KeysCommands::Combine { share_files } =>
+                shamir::combine_key(&keydir, &shamir::read_shares(&share_files)?, output)?,
+            KeysCommands::NewSeed { seed_file } => seed::new_seed(&seed_file, output)?,
+            KeysCommands::Derive { derivation_path, seed_file, json } =>
+                seed::derive(&seed_file, &derivation_path, access, output, json)?,
+            KeysCommands::Epoch { root_public_key, not_before } =>
+                epoch::new_epoch(&keydir, &root_public_key, &not_before, access, output)?,
+            KeysCommands::Shred { before } => epoch::shred(&keydir, &before, output)?,
+            KeysCommands::Bundle { public_key, not_after } =>
+                bundle::bundle(&keydir, &public_key, &not_after, access, output)?,
+            KeysCommands::Certify { org_public_key } =>
+                bundle::certify(&keydir, &org_public_key, input, access, output)?,
+        },
     }
 
     Ok(())
 }
+
+#[cfg(not(feature = "pkcs11"))]
+type Token = Option<()>;
+#[cfg(feature = "pkcs11")]
+type Token = Option<pkcs11::Token>;
+
+/// Decryption uses the given secret key, if there is one, or the agents, if there are any, or the
+/// PKCS#11 token, if there is one, or else reads secret keys from the key directories.
+fn store(keydirs: &[String], secret_key: SecretKeyArgs, agent_sockets: Vec<String>, token: Token,
+                                access: io::KeyAccess) -> anyhow::Result<Box<dyn KeyStore>> {
+    if let Some(fd) = secret_key.secret_key_fd {
+        return Ok(Box::new(FdStore::read(fd, access.passphrase)?));
+    }
+    if !secret_key.share_file.is_empty() {
+        return Ok(Box::new(shamir::combine_store(&shamir::read_shares(&secret_key.share_file)?)?));
+    }
+    if let Some(path) = secret_key.secret_key_file {
+        return Ok(Box::new(FdStore::open(&shellexpand::tilde(&path), access)?));
+    }
+    Ok(match (&agent_sockets[..], token) {
+        #[cfg(unix)]
+        ([socket], _) => Box::new(agent::AgentStore::new(socket)),
+        #[cfg(unix)]
+        ([_, ..], _) => Box::new(LayeredStore::new(agent_sockets.iter()
+            .map(|socket| Box::new(agent::AgentStore::new(socket)) as Box<dyn KeyStore>)
+            .collect())),
+        #[cfg(feature = "pkcs11")]
+        (_, Some(token)) => Box::new(token),
+        _ => search_path(keydirs, access),
+    })
+}
+
+/// The public keys to encrypt to: those given, and those of the bundles which a trust root
+/// certified.
+fn recipients(mut public_keys: Vec<String>, bundles: &[String], trust_roots: &[String])
+                                                                -> anyhow::Result<Vec<String>> {
+    if !bundles.is_empty() {
+        public_keys.extend(bundle::trusted_keys(bundles, trust_roots)?);
+    }
+    Ok(public_keys)
+}
+
+/// The key directories, searched in order.
+fn search_path(keydirs: &[String], access: io::KeyAccess) -> Box<dyn KeyStore> {
+    match keydirs {
+        // a lone directory reports which file it could not read
+        [keydir] => Box::new(DirectoryStore::new(keydir, access)),
+        keydirs => Box::new(LayeredStore::directories(keydirs, access)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Each error has its own exit status, even when it is wrapped in context.
+    #[test]
+    fn test_exit_codes() {
+        let code = |error: turnstile::Error| exit_code(&anyhow::Error::new(error).context("ctx"));
+        assert_eq!(code(turnstile::Error::BadMagic), EXIT_BAD_MAGIC);
+        assert_eq!(code(turnstile::Error::InvalidThreshold { threshold: 4, shares: 3 }),
+                                                                            EXIT_INVALID_HEADER);
+        assert_eq!(code(turnstile::Error::KeyNotFound("key".to_string())), EXIT_KEY_NOT_FOUND);
+        assert_eq!(code(turnstile::Error::BadShare { key: "key".to_string() }), EXIT_AUTHENTICATION);
+        assert_eq!(code(turnstile::Error::NoEndMarker { chunks: 1 }), EXIT_TRUNCATED);
+        assert_eq!(exit_code(&anyhow::anyhow!("anything else")), EXIT_FAILURE);
+    }
+
+    /// With a trust root, a bare public key is refused unless it is explicitly allowed.
+    #[test]
+    fn test_bare_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        let b62_pkey = turnstile::key::SecretKey::generate().public_key().to_string();
+        for (name, allow) in [("refused", &[][..]), ("allowed", &["--allow-bare-keys"])] {
+            let output = dir.path().join(name);
+            let args = [&["turnstile", "-i", "/dev/null", "-o", output.to_str().unwrap(),
+                    "encrypt", &b62_pkey, "--revocation-list", "/nonexistent/revoked.toml",
+                    "--trust-root", &b62_pkey][..], allow].concat();
+            match run(Cli::parse_from(args)) {
+                Err(e) => assert!(allow.is_empty() && e.to_string().starts_with("there are trust")),
+                Ok(()) => assert!(!allow.is_empty()),
+            }
+        }
+    }
+
+    /// The epoch keys of a manifest are not bare, so trust roots do not refuse them.
+    #[test]
+    fn test_manifest_with_trust_roots() {
+        let dir = tempfile::tempdir().unwrap();
+        let keydir = dir.path().to_str().unwrap();
+        let (root_pkey, root_skey) = sodiumoxide::crypto::box_::gen_keypair();
+        io::write_key_files(keydir, &root_pkey, &root_skey, None, None).unwrap();
+        let b62_root = turnstile::key::PublicKey::from(root_pkey).to_string();
+        run(Cli::parse_from(["turnstile", "-k", keydir, "-o", &format!("{keydir}/epoch.txt"),
+                        "keys", "epoch", &b62_root, "--not-before", "2020-01-01"])).unwrap();
+        let manifest = epoch::manifest_path(keydir, &b62_root);
+        let output = format!("{keydir}/out.t7e");
+        run(Cli::parse_from(["turnstile", "-i", "/dev/null", "-o", &output, "encrypt", &b62_root,
+                    "--manifest", &manifest, "--trust-root", &b62_root,
+                    "--revocation-list", "/nonexistent/revoked.toml"])).unwrap();
+        assert!(std::fs::metadata(&output).unwrap().len() > 0);
+    }
+
+    /// Only one place to find the secret key may be given.
+    #[test]
+    fn test_secret_key_sources() {
+        for args in [&["--secret-key-fd", "3", "--share-file", "a.share1"][..],
+                     &["--secret-key-file", "a.secret", "--secret-key-fd", "3"]] {
+            let args = [&["turnstile", "decrypt"][..], args].concat();
+            assert_eq!(Cli::try_parse_from(args).err().unwrap().kind(),
+                                                        clap::ErrorKind::ArgumentConflict);
+        }
+        let args = ["turnstile", "-i", "/dev/null", "--agent", "/nonexistent/agent.sock", "decrypt",
+                    "--secret-key-file", "/nonexistent/a.secret"];
+        assert!(run(Cli::parse_from(args)).err().unwrap().to_string().starts_with("give only one"));
+        #[cfg(feature = "pkcs11")]
+        assert_eq!(Cli::try_parse_from(["turnstile", "--agent", "agent.sock", "--pkcs11",
+            "module.so", "decrypt"]).err().unwrap().kind(), clap::ErrorKind::ArgumentConflict);
+    }
+
+    /// An export is created readable only by its owner, and never over an existing file.
+    #[cfg(unix)]
+    #[test]
+    fn test_export_mode() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::tempdir().unwrap();
+        let keydir = dir.path().to_str().unwrap();
+        let bundle = dir.path().join("keys.bundle");
+        let args = ["turnstile", "-k", keydir, "-o", bundle.to_str().unwrap(), "keys", "export"];
+        run(Cli::parse_from(args)).unwrap();
+        assert_eq!(std::fs::metadata(&bundle).unwrap().permissions().mode() & 0o777, 0o600);
+        assert!(run(Cli::parse_from(args)).is_err());
+    }
+
+    /// Options which keygen cannot honour on a token are refused, rather than writing a disk key.
+    #[cfg(feature = "pkcs11")]
+    #[test]
+    fn test_pkcs11_keygen_options() {
+        let keydir = tempfile::tempdir().unwrap();
+        let keydir = keydir.path().to_str().unwrap();
+        for option in [&["--passphrase"][..], &["--from-seed", "seed", "--derivation-path", "a/b"]] {
+            let args = [&["turnstile", "-k", keydir, "--pkcs11", "/nonexistent/module.so",
+                                                                            "keygen"][..], option];
+            let error = run(Cli::parse_from(args.concat())).err().unwrap();
+            assert!(error.to_string().starts_with("--pkcs11 keygen takes neither"));
+        }
+        assert_eq!(io::list_key_names(keydir).unwrap(), Vec::<String>::new());
+    }
+}
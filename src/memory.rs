@@ -0,0 +1,77 @@
+use std::ops::{Deref, DerefMut};
+use sodiumoxide::{crypto::box_::{PrecomputedKey, SecretKey}, utils};
+
+/// Secret material whose bytes can be locked into RAM.
+pub trait Lockable {
+    fn bytes_mut(&mut self) -> &mut [u8];
+}
+
+impl Lockable for [u8] {
+    fn bytes_mut(&mut self) -> &mut [u8] {
+        self
+    }
+}
+
+impl Lockable for SecretKey {
+    fn bytes_mut(&mut self) -> &mut [u8] {
+        &mut self.0
+    }
+}
+
+impl Lockable for PrecomputedKey {
+    fn bytes_mut(&mut self) -> &mut [u8] {
+        &mut self.0
+    }
+}
+
+/// Keys or plaintext on the heap, locked into RAM (where the OS allows) so that they are not
+/// swapped out, and zeroed when dropped.
+pub struct Locked<T: Lockable + ?Sized>(Box<T>);
+
+impl<T: Lockable + ?Sized> Locked<T> {
+    pub fn new(mut secret: Box<T>) -> Self {
+        // mlock fails if RLIMIT_MEMLOCK is exhausted, in which case we still zero on drop
+        let _ = utils::mlock(secret.bytes_mut());
+        Locked(secret)
+    }
+}
+
+impl Locked<[u8]> {
+    /// A zeroed buffer for plaintext.
+    pub fn buffer(len: usize) -> Self {
+        Locked::new(vec![0u8; len].into_boxed_slice())
+    }
+}
+
+impl<T: Lockable + ?Sized> Deref for Locked<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: Lockable + ?Sized> DerefMut for Locked<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+impl<T: Lockable + ?Sized> Drop for Locked<T> {
+    fn drop(&mut self) {
+        // munlock zeroes the memory before unlocking it
+        let _ = utils::munlock(self.0.bytes_mut());
+    }
+}
+
+/// Stop the process from writing core dumps, which could contain keys or plaintext.
+#[cfg(unix)]
+pub fn disable_core_dumps() {
+    let limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+    unsafe {
+        libc::setrlimit(libc::RLIMIT_CORE, &limit);
+    }
+}
+
+#[cfg(not(unix))]
+pub fn disable_core_dumps() {}
@@ -0,0 +1,121 @@
+//! Paper backups of secret keys, as 24 word BIP39 mnemonics, whose last word includes a checksum.
+//!
+//! The optional passphrase extension masks the secret key with a key derived from the passphrase,
+//! so that the words alone do not reveal it.
+
+use std::io::{Read, Write};
+
+use bip39::Mnemonic;
+use sodiumoxide::crypto::{box_::SecretKey, generichash, pwhash::argon2id13};
+use zeroize::Zeroizing;
+
+use crate::{base62, io::{write_key_files, disk_lookup_with, KeyAccess}, key, passphrase::Passphrase};
+
+/// The salt for passphrase extensions, which cannot be random as only the words are written down.
+const MNEMONIC_CONTEXT: &[u8] = b"turnstile mnemonic v1";
+
+const WORDS_PER_LINE: usize = 6;
+
+/// The words for a secret key, masked by the passphrase extension if there is one.
+pub fn to_words(skey: &SecretKey, extension: Option<&str>) -> anyhow::Result<Zeroizing<String>> {
+    let mut entropy = Zeroizing::new(skey.0);
+    if let Some(extension) = extension {
+        apply_extension(&mut entropy, extension)?;
+    }
+    let mnemonic = Mnemonic::from_entropy(&*entropy)
+        .map_err(|e| anyhow::anyhow!("unable to make a mnemonic: {e}"))?;
+    let words: Vec<&str> = mnemonic.words().collect();
+    Ok(Zeroizing::new(words.chunks(WORDS_PER_LINE)
+        .map(|line| line.join(" "))
+        .collect::<Vec<String>>()
+        .join("\n")))
+}
+
+/// The secret key for some words, checking their checksum.  A wrong passphrase extension cannot be
+/// detected, but gives a different public key.
+pub fn from_words(words: &str, extension: Option<&str>) -> anyhow::Result<SecretKey> {
+    let words = Zeroizing::new(words.lines()
+        .filter(|line| !line.starts_with('#'))
+        .collect::<Vec<&str>>()
+        .join(" "));
+    let mnemonic = Mnemonic::parse_normalized(&words.to_lowercase())
+        .map_err(|e| anyhow::anyhow!("invalid mnemonic: {e}"))?;
+    let entropy = Zeroizing::new(mnemonic.to_entropy());
+    let mut skey = Zeroizing::new([0u8; 32]);
+    if entropy.len() != skey.len() {
+        return Err(anyhow::anyhow!("a secret key mnemonic has 24 words, not {}",
+                                                                mnemonic.word_count()));
+    }
+    skey.copy_from_slice(&entropy);
+    if let Some(extension) = extension {
+        apply_extension(&mut skey, extension)?;
+    }
+    Ok(SecretKey(*skey))
+}
+
+/// XOR an Argon2id key, derived from the passphrase extension, into the secret key.
+fn apply_extension(skey: &mut [u8; 32], extension: &str) -> anyhow::Result<()> {
+    let salt_digest = generichash::hash(MNEMONIC_CONTEXT, Some(argon2id13::SALTBYTES), None)
+        .map_err(|_| anyhow::anyhow!("unable to derive the passphrase extension"))?;
+    let salt = argon2id13::Salt::from_slice(salt_digest.as_ref()).expect("the digest is a salt");
+    let mut mask = Zeroizing::new([0u8; 32]);
+    argon2id13::derive_key(&mut *mask, extension.as_bytes(), &salt,
+                            argon2id13::OPSLIMIT_INTERACTIVE, argon2id13::MEMLIMIT_INTERACTIVE)
+        .map_err(|_| anyhow::anyhow!("unable to derive the passphrase extension"))?;
+    for (byte, mask_byte) in skey.iter_mut().zip(mask.iter()) {
+        *byte ^= mask_byte;
+    }
+    Ok(())
+}
+
+/// Write the words for a secret key in the key directory.
+pub fn backup(keydir: &str, b62_pkey: &str, access: KeyAccess, extension: Option<Passphrase>,
+                                                output: &mut dyn Write) -> anyhow::Result<()> {
+    let pkey: key::PublicKey = b62_pkey.parse()?;
+    let skey = disk_lookup_with(keydir, &pkey.into(), access)?;
+    let extension = extension.map(|extension| extension.read_new()).transpose()?;
+    let words = to_words(&skey, extension.as_ref().map(|e| e.as_str()))?;
+    writeln!(output, "# turnstile secret key {pkey}{}",
+        if extension.is_some() { " (with a passphrase extension)" } else { "" })?;
+    writeln!(output, "{}", words.as_str())?;
+    Ok(())
+}
+
+/// Read the words for a secret key, and write its key files into the key directory.
+pub fn restore(keydir: &str, input: &mut dyn Read, extension: Option<Passphrase>,
+                                                output: &mut dyn Write) -> anyhow::Result<()> {
+    let mut words = Zeroizing::new(String::new());
+    input.read_to_string(&mut words)?;
+    let extension = extension.map(|extension| extension.read("Passphrase extension: "))
+                                                                                .transpose()?;
+    let skey = from_words(&words, extension.as_ref().map(|e| e.as_str()))?;
+    let pkey = skey.public_key();
+    let (path, _public_path) = write_key_files(keydir, &pkey, &skey, None, None)?;
+    writeln!(output, "restored secret key into {path}")?;
+    writeln!(output, "public key: {}", base62::encode(&pkey.0))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mnemonic() {
+        // a fixed key, so that the changed word below always fails the checksum
+        let skey = SecretKey([7u8; 32]);
+        let words = to_words(&skey, None).unwrap();
+        assert_eq!(words.split_whitespace().count(), 24);
+        assert_eq!(from_words(&format!("# comment\n{}\n", words.as_str()), None).unwrap(), skey);
+
+        // the checksum catches a changed word
+        let mut changed: Vec<&str> = words.split_whitespace().collect();
+        changed[3] = if changed[3] == "zoo" { "abandon" } else { "zoo" };
+        assert!(from_words(&changed.join(" "), None).is_err());
+
+        let extended = to_words(&skey, Some("correct horse")).unwrap();
+        assert_ne!(extended, words);
+        assert_eq!(from_words(&extended, Some("correct horse")).unwrap(), skey);
+        assert_ne!(from_words(&extended, Some("wrong horse")).unwrap(), skey);
+    }
+}
@@ -0,0 +1,55 @@
+use anyhow::Context;
+use zeroize::Zeroizing;
+
+/// Where passphrases for secret key files come from.
+#[derive(Clone, Copy)]
+pub enum Passphrase {
+    /// Ask on the terminal.
+    Prompt,
+    /// Read a line at a time from an inherited file descriptor, so that scripts can supply them.
+    Fd(i32),
+}
+
+impl Passphrase {
+    /// Read one passphrase.
+    pub fn read(&self, prompt: &str) -> anyhow::Result<Zeroizing<String>> {
+        match self {
+            Passphrase::Prompt => Ok(Zeroizing::new(rpassword::prompt_password(prompt)
+                .context("unable to read a passphrase from the terminal")?)),
+            Passphrase::Fd(fd) => read_line_from_fd(*fd),
+        }
+    }
+
+    /// Read a new passphrase, asking twice when prompting.
+    pub fn read_new(&self) -> anyhow::Result<Zeroizing<String>> {
+        let passphrase = self.read("New passphrase: ")?;
+        if let Passphrase::Prompt = self {
+            if self.read("Repeat the new passphrase: ")? != passphrase {
+                return Err(anyhow::anyhow!("the passphrases do not match"));
+            }
+        }
+        Ok(passphrase)
+    }
+}
+
+#[cfg(unix)]
+fn read_line_from_fd(fd: i32) -> anyhow::Result<Zeroizing<String>> {
+    use std::io::{BufRead, BufReader};
+
+    // the fd may hold further passphrases, so read it unbuffered
+    let file = crate::io::inherited_fd(fd)?;
+    let mut line = Zeroizing::new(String::new());
+    BufReader::with_capacity(1, &file).read_line(&mut line)
+        .context(format!("unable to read a passphrase from file descriptor {fd}"))?;
+    if line.is_empty() {
+        return Err(anyhow::anyhow!("no passphrase on file descriptor {fd}"));
+    }
+    let len = line.trim_end_matches(['\r', '\n']).len();
+    line.truncate(len);
+    Ok(line)
+}
+
+#[cfg(not(unix))]
+fn read_line_from_fd(_fd: i32) -> anyhow::Result<Zeroizing<String>> {
+    Err(anyhow::anyhow!("passphrases can only be read from file descriptors on unix"))
+}
@@ -0,0 +1,246 @@
+//! Target keys held on a PKCS#11 token (an HSM, smartcard or SoftHSM), which never leave it.
+//!
+//! Keys are X25519 (`CKK_EC_MONTGOMERY`) keypairs, whose `CKA_ID` is the public key.  The token
+//! performs the ECDH step, and only the shared secret is read back to derive the precomputed key.
+
+use std::{io::Write, ops::Deref, sync::{Mutex, MutexGuard, PoisonError}};
+
+use anyhow::Context;
+use cryptoki::{context::{CInitializeArgs, Pkcs11}, mechanism::{elliptic_curve::{EcKdf,
+    Ecdh1DeriveParams}, Mechanism}, object::{Attribute, AttributeType, KeyType, ObjectClass},
+    session::{Session, UserType}, types::AuthPin};
+use sodiumoxide::crypto::box_::{PublicKey, PrecomputedKey, PRECOMPUTEDKEYBYTES};
+use zeroize::Zeroizing;
+
+use crate::{base62, error::Error, io::write_pub_file, key, keystore::KeyStore,
+            passphrase::Passphrase};
+
+/// DER encoding of the X25519 curve's OID, 1.3.101.110.
+const X25519_EC_PARAMS: [u8; 5] = [0x06, 0x03, 0x2b, 0x65, 0x6e];
+
+/// A PKCS#11 module, and how to get the user PIN of its first initialized token.  The session is
+/// opened, and the PIN read, on first use, then reused.
+pub struct Token {
+    module: String,
+    pin: Passphrase,
+    session: Cached<Session>,
+}
+
+impl Token {
+    pub fn new(module: String, pin: Passphrase) -> Self {
+        Token { module, pin, session: Cached(Mutex::new(None)) }
+    }
+
+    /// The logged in, read-write session on the first initialized token.
+    fn session(&self) -> anyhow::Result<CachedGuard<'_, Session>> {
+        self.session.get(|| self.open_session())
+    }
+
+    fn open_session(&self) -> anyhow::Result<Session> {
+        let pkcs11 = Pkcs11::new(&self.module)
+            .context(format!("unable to load PKCS#11 module '{}'", self.module))?;
+        pkcs11.initialize(CInitializeArgs::OsThreads)?;
+        let slot = *pkcs11.get_slots_with_initialized_token()?.first()
+            .ok_or_else(|| anyhow::anyhow!("no initialized token in '{}'", self.module))?;
+        let session = pkcs11.open_rw_session(slot)?;
+        let pin = self.pin.read("PKCS#11 user PIN: ")?;
+        session.login(UserType::User, Some(&AuthPin::new(pin.to_string())))
+            .context("unable to log in to the token")?;
+        Ok(session)
+    }
+}
+
+/// A value made on first use.  A failure to make it is returned, and the next use tries again.
+struct Cached<T>(Mutex<Option<T>>);
+
+impl<T> Cached<T> {
+    fn get(&self, make: impl FnOnce() -> anyhow::Result<T>) -> anyhow::Result<CachedGuard<'_, T>> {
+        let mut guard = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        if guard.is_none() {
+            *guard = Some(make()?);
+        }
+        Ok(CachedGuard(guard))
+    }
+}
+
+/// Holds the lock on a value which has been made.
+struct CachedGuard<'a, T>(MutexGuard<'a, Option<T>>);
+
+impl<T> Deref for CachedGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.0.as_ref().expect("the value was made before the guard")
+    }
+}
+
+/// Generate a keypair on the token, writing only `<PUBLIC_KEY>.pub` into the key directory.
+pub fn keygen(keydir: &str, token: &Token, label: Option<&str>, output: &mut dyn Write,
+                                                            json: bool) -> anyhow::Result<()> {
+    let session = token.session()?;
+    let public_template = [
+        Attribute::Token(true),
+        Attribute::EcParams(X25519_EC_PARAMS.to_vec()),
+    ];
+    let private_template = [
+        Attribute::Token(true),
+        Attribute::Private(true),
+        Attribute::Sensitive(true),
+        Attribute::Extractable(false),
+        Attribute::Derive(true),
+    ];
+    let (public_handle, private_handle) = session.generate_key_pair(
+        &Mechanism::EccMontgomeryKeyPairGen, &public_template, &private_template)
+        .context("unable to generate an X25519 keypair on the token")?;
+
+    let pkey = match session.get_attributes(public_handle, &[AttributeType::EcPoint])?.first() {
+        Some(Attribute::EcPoint(point)) => ec_point_to_public_key(point)?,
+        _ => return Err(anyhow::anyhow!("the token did not return the public key")),
+    };
+    let b62_pkey = base62::encode(&pkey.0);
+    let label = label.unwrap_or(&b62_pkey).as_bytes().to_vec();
+    for handle in [public_handle, private_handle] {
+        session.update_attributes(handle, &[Attribute::Id(pkey.0.to_vec()),
+                                            Attribute::Label(label.clone())])?;
+    }
+    let public_path = write_pub_file(keydir, &pkey, std::str::from_utf8(&label).ok())?;
+
+    if json {
+        writeln!(output, "{}", serde_json::json!({
+            "public_key": b62_pkey,
+            "label": std::str::from_utf8(&label).ok(),
+            "pkcs11_module": token.module,
+            "public_key_file": public_path,
+        }))?;
+    } else {
+        writeln!(output, "new secret key generated on the token in {}", token.module)?;
+        writeln!(output, "public key: {b62_pkey}")?;
+    }
+    Ok(())
+}
+
+/// Tokens return `CKA_EC_POINT` either raw or wrapped in a DER OCTET STRING.
+fn ec_point_to_public_key(point: &[u8]) -> anyhow::Result<PublicKey> {
+    let raw = match point {
+        [0x04, 0x20, raw @ ..] if raw.len() == 32 => raw,
+        raw => raw,
+    };
+    PublicKey::from_slice(raw)
+        .ok_or_else(|| anyhow::anyhow!("the token returned a {} byte public key", raw.len()))
+}
+
+/// Ask the token to perform X25519 with the target's secret key, and derive the precomputed key.
+pub fn token_precompute_key(session: &Session, source_pkey: &PublicKey, target_pkey: &PublicKey)
+                                                            -> anyhow::Result<PrecomputedKey> {
+    let private_handle = *session.find_objects(&[
+        Attribute::Class(ObjectClass::PRIVATE_KEY),
+        Attribute::KeyType(KeyType::EC_MONTGOMERY),
+        Attribute::Id(target_pkey.0.to_vec()),
+    ])?.first().ok_or_else(|| anyhow::Error::new(Error::KeyNotFound(base62::encode(&target_pkey.0)))
+        .context("the token does not hold the key"))?;
+
+    let params = Ecdh1DeriveParams::new(EcKdf::null(), &source_pkey.0);
+    let shared_template = [
+        Attribute::Class(ObjectClass::SECRET_KEY),
+        Attribute::KeyType(KeyType::GENERIC_SECRET),
+        Attribute::ValueLen(32.into()),
+        Attribute::Token(false),
+        Attribute::Sensitive(false),
+        Attribute::Extractable(true),
+    ];
+    let shared_handle = session.derive_key(&Mechanism::Ecdh1Derive(params), private_handle,
+                                            &shared_template).context("the token's ECDH failed")?;
+    let attributes = session.get_attributes(shared_handle, &[AttributeType::Value]);
+    session.destroy_object(shared_handle)?;
+    match attributes?.first() {
+        Some(Attribute::Value(shared)) => {
+            let shared = Zeroizing::new(shared.clone());
+            precompute_from_shared(&shared)
+        },
+        _ => Err(anyhow::anyhow!("the token did not return the shared secret")),
+    }
+}
+
+/// `box_::precompute` is HSalsa20 of the X25519 shared secret, with a zero nonce.
+fn precompute_from_shared(shared: &[u8]) -> anyhow::Result<PrecomputedKey> {
+    if shared.len() != 32 {
+        return Err(anyhow::anyhow!("the token returned a {} byte shared secret", shared.len()));
+    }
+    let mut symkey = PrecomputedKey([0u8; PRECOMPUTEDKEYBYTES]);
+    let zero_nonce = [0u8; 16];
+    unsafe {
+        libsodium_sys::crypto_core_hsalsa20(symkey.0.as_mut_ptr(), zero_nonce.as_ptr(),
+                                                    shared.as_ptr(), std::ptr::null());
+    }
+    Ok(symkey)
+}
+
+/// The target keys held on the token, which only returns precomputed keys.
+impl KeyStore for Token {
+    fn find(&self, pkey: &key::PublicKey) -> anyhow::Result<key::SecretKey> {
+        Err(anyhow::anyhow!("the token does not reveal the secret key for {pkey}"))
+    }
+
+    fn candidates(&self) -> anyhow::Result<Vec<key::PublicKey>> {
+        let session = self.session()?;
+        let mut pkeys = Vec::new();
+        for handle in session.find_objects(&[Attribute::Class(ObjectClass::PRIVATE_KEY),
+                                             Attribute::KeyType(KeyType::EC_MONTGOMERY)])? {
+            if let Some(Attribute::Id(id)) = session.get_attributes(handle, &[AttributeType::Id])?
+                                                                                    .first() {
+                pkeys.extend(PublicKey::from_slice(id).map(key::PublicKey::from));
+            }
+        }
+        Ok(pkeys)
+    }
+
+    fn precompute(&self, source_pkey: &key::PublicKey, target_pkey: &key::PublicKey)
+                                                        -> anyhow::Result<key::PrecomputedKey> {
+        let session = self.session()?;
+        Ok(token_precompute_key(&session, &(*source_pkey).into(), &(*target_pkey).into())?.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sodiumoxide::crypto::{box_, scalarmult::curve25519};
+    use super::*;
+
+    #[test]
+    fn test_precompute_from_shared() {
+        let (source_pkey, source_skey) = box_::gen_keypair();
+        let (target_pkey, target_skey) = box_::gen_keypair();
+        let shared = curve25519::scalarmult(&curve25519::Scalar(target_skey.0),
+                                            &curve25519::GroupElement(source_pkey.0)).unwrap();
+        assert_eq!(precompute_from_shared(&shared.0).unwrap(),
+                                                box_::precompute(&target_pkey, &source_skey));
+    }
+
+    #[test]
+    fn test_ec_point_to_public_key() {
+        let (pkey, _) = box_::gen_keypair();
+        let mut wrapped = vec![0x04, 0x20];
+        wrapped.extend_from_slice(&pkey.0);
+        assert_eq!(ec_point_to_public_key(&wrapped).unwrap(), pkey);
+        assert_eq!(ec_point_to_public_key(&pkey.0).unwrap(), pkey);
+    }
+
+    /// The PIN is read once, when the session is first opened, and not by later calls.
+    #[cfg(unix)]
+    #[test]
+    fn test_session_reused() {
+        use std::{io::{Seek, SeekFrom}, os::fd::AsRawFd};
+
+        let mut pins = tempfile::tempfile().unwrap();
+        writeln!(pins, "1234").unwrap();
+        pins.seek(SeekFrom::Start(0)).unwrap();
+        let pin = Passphrase::Fd(pins.as_raw_fd());
+        let sessions = Cached(Mutex::new(None));
+        assert!(sessions.get(|| Err(anyhow::anyhow!("no token"))).is_err());
+        for _ in 0..3 {
+            // a second read would find no PIN left
+            let session = sessions.get(|| pin.read("PKCS#11 user PIN: ")).unwrap();
+            assert_eq!(session.as_str(), "1234");
+        }
+    }
+}
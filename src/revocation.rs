@@ -0,0 +1,154 @@
+//! Revocation lists, so that sources stop encrypting to the keys of compromised targets.
+//!
+//! Each revocation is signed by the revoked key itself, so that anyone holding the secret key can
+//! revoke it, and nobody else can.  A list is any number of revocations, and revocations from
+//! `turnstile revoke` can simply be appended to it.
+//!
+//! ```toml
+//! # turnstile revocation
+//! [[revocations]]
+//! public_key = "i8q8p2L8gZpZsPD8NRcTiFfQHLfrhoq3IvsaEwWzPJH"
+//! revoked = "2026-10-19T09:14:02Z"
+//! reason = "web01 was compromised"
+//! signature = "c3d1...8e02"
+//! ```
+
+use std::{fs, io::Write, time::{SystemTime, UNIX_EPOCH}};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use sodiumoxide::crypto::box_::SecretKey;
+
+use crate::{base62, common::{format_time, parse_time}, io::{disk_lookup_with, KeyAccess}, key,
+            signature::{self, Signature}};
+
+/// Separates revocation signatures from any other use of the revoked key.
+const REVOKE_CONTEXT: &[u8] = b"turnstile revoke v1\0";
+
+#[derive(Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RevocationList {
+    #[serde(default)]
+    pub revocations: Vec<Revocation>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Revocation {
+    pub public_key: String,
+    /// RFC 3339
+    pub revoked: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    /// hex XEdDSA signature, by the revoked key.
+    pub signature: String,
+}
+
+impl Revocation {
+    /// A revocation of `skey`'s public key, signed by `skey`.
+    pub fn new(skey: &SecretKey, reason: Option<&str>) -> anyhow::Result<Revocation> {
+        let mut revocation = Revocation {
+            public_key: base62::encode(&skey.public_key().0),
+            revoked: format_time(SystemTime::now()),
+            reason: reason.map(str::to_string),
+            signature: String::new(),
+        };
+        revocation.signature = signature::sign(skey, &revocation.signed_bytes()?).to_hex();
+        Ok(revocation)
+    }
+
+    /// The bytes which are signed: the public key, the time and the reason.
+    fn signed_bytes(&self) -> anyhow::Result<Vec<u8>> {
+        let mut bytes = REVOKE_CONTEXT.to_vec();
+        bytes.extend_from_slice(self.public_key.parse::<key::PublicKey>()?.as_bytes());
+        bytes.extend_from_slice(&parse_time(&self.revoked)?.duration_since(UNIX_EPOCH)?.as_secs()
+                                                                                .to_be_bytes());
+        let reason = self.reason.as_deref().unwrap_or_default().as_bytes();
+        bytes.extend_from_slice(&(reason.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(reason);
+        Ok(bytes)
+    }
+
+    pub fn verify(&self) -> anyhow::Result<()> {
+        let pkey = self.public_key.parse::<key::PublicKey>()?.into();
+        signature::verify(&pkey, &self.signed_bytes()?,
+                                                            &Signature::from_hex(&self.signature)?)
+            .context(format!("the revocation of {} is invalid", self.public_key))
+    }
+}
+
+impl RevocationList {
+    /// Parse a list, checking the signature of every revocation in it.
+    pub fn parse(contents: &str) -> anyhow::Result<RevocationList> {
+        let list: RevocationList = toml::from_str(contents)?;
+        for revocation in &list.revocations {
+            revocation.verify()?;
+        }
+        Ok(list)
+    }
+
+    /// Read a list, which is empty if the file does not exist.
+    pub fn load(path: &str) -> anyhow::Result<RevocationList> {
+        match fs::read_to_string(path) {
+            Ok(contents) => RevocationList::parse(&contents)
+                .context(format!("invalid revocation list '{path}'")),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(RevocationList::default()),
+            Err(e) => Err(e).context(format!("unable to read revocation list '{path}'")),
+        }
+    }
+
+    /// Refuse a revoked public key.  The decoded keys are compared, rather than their text.
+    pub fn check(&self, b62_pkey: &str) -> anyhow::Result<()> {
+        let pkey: key::PublicKey = b62_pkey.parse()?;
+        match self.revocations.iter().find(|revocation|
+                revocation.public_key.parse::<key::PublicKey>().is_ok_and(|k| k == pkey)) {
+            Some(revocation) => Err(anyhow::anyhow!("{b62_pkey} was revoked at {}{}",
+                revocation.revoked,
+                revocation.reason.as_ref().map(|reason| format!(": {reason}")).unwrap_or_default())),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Write a revocation of a key in the key directory, for appending to revocation lists.
+pub fn revoke(keydir: &str, b62_pkey: &str, reason: Option<&str>, access: KeyAccess,
+                                                output: &mut dyn Write) -> anyhow::Result<()> {
+    if reason.is_some_and(|reason| reason.contains('\n')) {
+        return Err(anyhow::anyhow!("the reason must be a single line"));
+    }
+    let skey = disk_lookup_with(keydir, &b62_pkey.parse::<key::PublicKey>()?.into(), access)?;
+    let list = RevocationList { revocations: vec![Revocation::new(&skey, reason)?] };
+    write!(output, "# turnstile revocation\n{}", toml::to_string(&list)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use sodiumoxide::crypto::box_;
+    use super::*;
+
+    #[test]
+    fn test_revocation_list() {
+        let (pkey, skey) = box_::gen_keypair();
+        let (other_pkey, other_skey) = box_::gen_keypair();
+        let b62_pkey = base62::encode(&pkey.0);
+        let mut contents = String::new();
+        for (skey, reason) in [(&skey, Some("web01 was compromised")), (&other_skey, None)] {
+            let list = RevocationList { revocations: vec![Revocation::new(skey, reason).unwrap()] };
+            contents.push_str(&toml::to_string(&list).unwrap());
+        }
+
+        let list = RevocationList::parse(&contents).unwrap();
+        assert!(list.check(&b62_pkey).err().unwrap().to_string()
+                                                        .ends_with(": web01 was compromised"));
+        assert!(list.check(&base62::encode(&other_pkey.0)).is_err());
+        assert!(list.check(&base62::encode(&box_::gen_keypair().0.0)).is_ok());
+        assert!(list.check(&format!("{b62_pkey}Z")).err().unwrap().to_string()
+                                                        .contains("exactly 43 base62 characters"));
+
+        // nobody else can revoke a key
+        let mut forged = Revocation::new(&other_skey, None).unwrap();
+        forged.public_key = b62_pkey;
+        assert!(forged.verify().is_err());
+    }
+}
@@ -0,0 +1,114 @@
+//! Where encryption gets its randomness, so that tests and known-answer vectors can make it
+//! deterministic.
+//!
+//! Encryption draws, in order: the ephemeral secret key (32 bytes), the initial nonce (24 bytes),
+//! then for threshold streams the content key (32 bytes) and the Shamir coefficients (32 bytes for
+//! each of threshold - 1).
+
+use sodiumoxide::{crypto::{box_::{Nonce, SecretKey}, stream::chacha20}, randombytes};
+use zeroize::Zeroizing;
+
+pub trait Rng {
+    fn fill(&mut self, buf: &mut [u8]);
+}
+
+/// libsodium's `randombytes`, which encryption uses unless it is given another `Rng`.
+#[derive(Clone, Copy, Default)]
+pub struct OsRng;
+
+impl Rng for OsRng {
+    fn fill(&mut self, buf: &mut [u8]) {
+        randombytes::randombytes_into(buf);
+    }
+}
+
+/// The ChaCha20 keystream of a 32 byte seed, with a nonce of zero.  Anyone with the seed can
+/// reproduce everything drawn from it, so it is only for testing.
+pub struct SeededRng {
+    key: chacha20::Key,
+    /// The keystream block which the last draw ended in, and how much of it was drawn.
+    block: Zeroizing<[u8; BLOCK_LEN]>,
+    drawn: usize,
+    /// The counter of the next whole block.
+    counter: u64,
+}
+
+const BLOCK_LEN: usize = 64;
+const ZERO_NONCE: chacha20::Nonce = chacha20::Nonce([0u8; 8]);
+
+impl SeededRng {
+    pub fn new(seed: [u8; 32]) -> Self {
+        SeededRng { key: chacha20::Key(seed), block: Zeroizing::new([0u8; BLOCK_LEN]),
+                    drawn: BLOCK_LEN, counter: 0 }
+    }
+}
+
+impl Rng for SeededRng {
+    fn fill(&mut self, buf: &mut [u8]) {
+        let from_block = buf.len().min(BLOCK_LEN - self.drawn);
+        let (head, rest) = buf.split_at_mut(from_block);
+        head.copy_from_slice(&self.block[self.drawn..self.drawn + from_block]);
+        self.drawn += from_block;
+        if rest.is_empty() {
+            return;
+        }
+
+        // the rest of the draw starts at the next whole block
+        rest.fill(0);
+        chacha20::stream_xor_ic_inplace(rest, &ZERO_NONCE, self.counter, &self.key);
+        self.counter += rest.len().div_ceil(BLOCK_LEN) as u64;
+        let partial = rest.len() % BLOCK_LEN;
+        if partial != 0 {
+            self.block.fill(0);
+            chacha20::stream_xor_ic_inplace(&mut self.block[..], &ZERO_NONCE, self.counter - 1,
+                                            &self.key);
+            self.drawn = partial;
+        }
+    }
+}
+
+pub fn gen_secret_key(rng: &mut dyn Rng) -> SecretKey {
+    let mut skey = SecretKey([0u8; 32]);
+    rng.fill(&mut skey.0);
+    skey
+}
+
+pub fn gen_nonce(rng: &mut dyn Rng) -> Nonce {
+    let mut nonce = Nonce([0u8; 24]);
+    rng.fill(&mut nonce.0);
+    nonce
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seeded_rng() {
+        let mut whole = [0u8; 100];
+        SeededRng::new([1u8; 32]).fill(&mut whole);
+        let mut rng = SeededRng::new([1u8; 32]);
+        let mut pieces = [0u8; 100];
+        for piece in pieces.chunks_mut(30) {
+            rng.fill(piece);
+        }
+        assert_eq!(pieces, whole);
+
+        // draws which end on, span and fall within blocks all continue the same keystream
+        let mut long = vec![0u8; 1000];
+        SeededRng::new([1u8; 32]).fill(&mut long);
+        let mut rng = SeededRng::new([1u8; 32]);
+        let mut drawn = Vec::new();
+        for len in [64, 1, 63, 200, 0, 5, 128, 539] {
+            let mut piece = vec![0u8; len];
+            rng.fill(&mut piece);
+            drawn.extend_from_slice(&piece);
+        }
+        assert_eq!(drawn, long);
+        assert_eq!(long[..100], whole);
+
+        let mut other = [0u8; 100];
+        SeededRng::new([2u8; 32]).fill(&mut other);
+        assert_ne!(other, whole);
+    }
+}
@@ -0,0 +1,118 @@
+//! Target keys derived deterministically from a master seed and a derivation path, such as
+//! `host/web01/2026-10`, so that a single backup of the seed can regenerate any of them.
+
+use std::io::{Read, Write};
+
+use anyhow::Context;
+use sodiumoxide::{crypto::{box_::{PublicKey, SecretKey}, generichash}, randombytes};
+use zeroize::Zeroizing;
+
+use crate::{base62, io::{create_key_file, open_secret_file, KeyAccess}, memory::Locked};
+
+/// Separates derived keys from any other use of the seed.
+const DERIVE_CONTEXT: &[u8] = b"turnstile derive v1\0";
+
+/// A master seed, which is 32 random bytes.
+pub struct Seed(Locked<[u8]>);
+
+impl Seed {
+    pub fn generate() -> Seed {
+        let mut seed = Locked::buffer(32);
+        randombytes::randombytes_into(&mut seed);
+        Seed(seed)
+    }
+
+    pub fn from_bytes(bytes: &[u8; 32]) -> Seed {
+        let mut seed = Locked::buffer(32);
+        seed.copy_from_slice(bytes);
+        Seed(seed)
+    }
+
+    /// Derive the keypair for `path`.  The secret key is a keyed BLAKE2b hash of the path, which
+    /// X25519 clamps as it does any other secret key.
+    pub fn derive(&self, path: &str) -> anyhow::Result<(PublicKey, SecretKey)> {
+        check_derivation_path(path)?;
+        let mut state = generichash::State::new(Some(32), Some(&self.0))
+            .map_err(|_| anyhow::anyhow!("unable to derive a key"))?;
+        state.update(DERIVE_CONTEXT).and_then(|_| state.update(path.as_bytes()))
+            .map_err(|_| anyhow::anyhow!("unable to derive a key"))?;
+        let digest = state.finalize().map_err(|_| anyhow::anyhow!("unable to derive a key"))?;
+        let skey = SecretKey::from_slice(digest.as_ref()).expect("the digest is 32 bytes");
+        Ok((skey.public_key(), skey))
+    }
+
+    fn to_base62(&self) -> Zeroizing<String> {
+        let mut bytes = Zeroizing::new([0u8; 32]);
+        bytes.copy_from_slice(&self.0);
+        Zeroizing::new(base62::encode(&bytes))
+    }
+}
+
+/// Derivation paths are `/` separated, non-empty, printable components.
+pub fn check_derivation_path(path: &str) -> anyhow::Result<()> {
+    let invalid = |c: char| c.is_control() || c.is_whitespace();
+    if path.split('/').any(|component| component.is_empty() || component.contains(invalid)) {
+        return Err(anyhow::anyhow!("invalid derivation path '{path}', which should look like \
+                                                                        'host/web01/2026-10'"));
+    }
+    Ok(())
+}
+
+/// Read a seed file, which contains only the base62 seed.
+pub fn read_seed_file(path: &str, access: KeyAccess) -> anyhow::Result<Seed> {
+    let mut contents = Zeroizing::new(String::new());
+    open_secret_file(path, "a seed", access)?.read_to_string(&mut contents)
+        .context(format!("unable to read a seed from '{path}'"))?;
+    let bytes = Zeroizing::new(base62::decode_exact(contents.trim_end())
+        .context(format!("invalid seed file '{path}'"))?);
+    Ok(Seed::from_bytes(&bytes))
+}
+
+/// Generate a new master seed, writing it into a new file which only its owner can read.
+pub fn new_seed(path: &str, output: &mut dyn Write) -> anyhow::Result<()> {
+    let seed = Seed::generate();
+    let mut file = create_key_file(path, 0o600)?;
+    writeln!(file, "{}", seed.to_base62().as_str())
+        .context(format!("unable to write a seed into '{path}'"))?;
+    writeln!(output, "new master seed written into {path}")?;
+    Ok(())
+}
+
+/// Show the public key for a derivation path, so that sources can be given future keys without
+/// writing their secret keys anywhere.
+pub fn derive(seed_path: &str, path: &str, access: KeyAccess, output: &mut dyn Write, json: bool)
+                                                                        -> anyhow::Result<()> {
+    let (pkey, _skey) = read_seed_file(seed_path, access)?.derive(path)?;
+    let b62_pkey = base62::encode(&pkey.0);
+    if json {
+        writeln!(output, "{}", serde_json::json!({
+            "derivation_path": path,
+            "public_key": b62_pkey,
+        }))?;
+    } else {
+        writeln!(output, "{b62_pkey} {path}")?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_derive() {
+        let seed = Seed::from_bytes(&[7u8; 32]);
+        let (pkey, skey) = seed.derive("host/web01/2026-10").unwrap();
+        // derived keys must never change, or seeds could no longer regenerate them
+        assert_eq!(crate::common::hex(&skey.0),
+                            "a00e53fcce249c0f13bf49d96fc8b1793b32f3c0ed433492171dafc02bb78aa1");
+        assert_eq!(skey.public_key(), pkey);
+        assert_eq!(seed.derive("host/web01/2026-10").unwrap().1, skey);
+        assert_ne!(seed.derive("host/web01/2026-11").unwrap().1, skey);
+        assert_ne!(Seed::from_bytes(&[8u8; 32]).derive("host/web01/2026-10").unwrap().1, skey);
+
+        assert!(seed.derive("").is_err());
+        assert!(seed.derive("host//2026-10").is_err());
+        assert!(seed.derive("host/web 01").is_err());
+    }
+}
@@ -0,0 +1,224 @@
+//! Known-answer test vectors, which check this build against streams written by a reference build.
+//!
+//! The vectors for each format version are in `vectors/<major>.<minor>.toml`, which describes how
+//! each stream was made, so that other implementations of the format can check themselves too.
+
+use std::{fs, io::{Read, Write}};
+
+use anyhow::Context;
+use serde::Deserialize;
+use sodiumoxide::crypto::generichash;
+
+use crate::{common::*, decrypt::{read_header, Decryptor}, encrypt::Encryptor, key,
+            keystore::MemoryStore, rng::SeededRng, stream::DecryptReader};
+
+/// The vectors for the format version which this build writes.
+pub const VECTORS: &str = include_str!("../vectors/1.0.toml");
+
+/// Where the patch version of the writer is, in both kinds of stream.  Only it may differ from the
+/// vectors'.
+const PATCH_VERSION_OFFSET: usize = FADEDBEE.len() + TURNSTILE.len() + 2;
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Vectors {
+    /// The version which wrote the vectors.
+    version: String,
+    vector: Vec<Vector>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Vector {
+    name: String,
+    target_secret_keys: Vec<String>,
+    threshold: Option<u8>,
+    rng_seed: String,
+    plaintext_len: usize,
+    source_public_key: String,
+    initial_nonce: String,
+    ciphertext: Option<String>,
+    ciphertext_blake2b: Option<String>,
+}
+
+/// Check the vectors in the file at `path`, or the built-in ones.
+pub fn selftest(path: Option<&str>, output: &mut dyn Write) -> anyhow::Result<()> {
+    match path {
+        Some(path) => check(&fs::read_to_string(path)
+            .context(format!("unable to read test vectors from '{path}'"))?, output),
+        None => check(VECTORS, output),
+    }
+}
+
+/// Check every vector in `vectors`, reporting each one which passes.
+pub fn check(vectors: &str, output: &mut dyn Write) -> anyhow::Result<()> {
+    let vectors: Vectors = toml::from_str(vectors).context("invalid test vectors")?;
+    let version = version_from_str(&vectors.version)?;
+    for vector in &vectors.vector {
+        vector.check(&version).with_context(|| format!("test vector '{}' failed", vector.name))?;
+        writeln!(output, "ok: {}", vector.name)?;
+    }
+    writeln!(output, "OK: {} test vectors passed", vectors.vector.len())?;
+    Ok(())
+}
+
+impl Vector {
+    fn check(&self, version: &[u8; 3]) -> anyhow::Result<()> {
+        let skeys = self.secret_keys()?;
+        let plaintext = plaintext(self.plaintext_len);
+        let mut encrypted = Vec::new();
+        self.encryptor(&skeys)?.encrypt(&mut plaintext.as_slice(), &mut encrypted)?;
+        encrypted[PATCH_VERSION_OFFSET] = version[2];
+
+        let header = read_header(&mut encrypted.as_slice())?;
+        if header.source_key.to_string() != self.source_public_key {
+            return Err(anyhow::anyhow!("the ephemeral key is {}", header.source_key));
+        }
+        if hex(&header.initial_nonce) != self.initial_nonce {
+            return Err(anyhow::anyhow!("the initial nonce is {}", hex(&header.initial_nonce)));
+        }
+        let expected = self.expected(&encrypted)?;
+
+        let store = self.store(&skeys);
+        let mut decrypted = Vec::new();
+        Decryptor::new(&store).require_end_marker(true)
+            .decrypt(&mut expected.as_slice(), &mut decrypted)?;
+        if decrypted != plaintext {
+            return Err(anyhow::anyhow!("the plaintext differs"));
+        }
+
+        // the streaming adapters write (to a single recipient) and read the same streams
+        if self.threshold.is_none() {
+            let mut writer = self.encryptor(&skeys)?.writer(Vec::new())?;
+            writer.write_all(&plaintext)?;
+            let mut written = writer.finish()?;
+            written[PATCH_VERSION_OFFSET] = version[2];
+            if written != expected {
+                return Err(anyhow::anyhow!("the EncryptWriter's ciphertext differs"));
+            }
+        }
+        let mut reader = DecryptReader::new(expected.as_slice(), &store)?;
+        let mut read = Vec::new();
+        reader.read_to_end(&mut read)?;
+        if read != plaintext || !reader.summary().is_some_and(|summary| summary.end_marker) {
+            return Err(anyhow::anyhow!("the DecryptReader's plaintext differs"));
+        }
+        Ok(())
+    }
+
+    fn secret_keys(&self) -> anyhow::Result<Vec<key::SecretKey>> {
+        self.target_secret_keys.iter().map(|b62| b62.parse()).collect()
+    }
+
+    /// An encryptor for the recipients, drawing from the vector's rng.
+    fn encryptor(&self, skeys: &[key::SecretKey]) -> anyhow::Result<Encryptor> {
+        let pkeys: Vec<key::PublicKey> = skeys.iter().map(key::SecretKey::public_key).collect();
+        let seed = <[u8; 32]>::try_from(unhex(&self.rng_seed)?)
+            .map_err(|_| anyhow::anyhow!("the rng seed is not 32 bytes"))?;
+        let encryptor = match self.threshold {
+            Some(threshold) => Encryptor::threshold(&pkeys, threshold),
+            None => Encryptor::new(pkeys[0]),
+        };
+        Ok(encryptor.rng(SeededRng::new(seed)))
+    }
+
+    /// The vector's ciphertext, given what this build encrypted.
+    fn expected(&self, encrypted: &[u8]) -> anyhow::Result<Vec<u8>> {
+        // a long stream is only recorded by its hash, so decrypt what was encrypted instead
+        let expected = match (&self.ciphertext, &self.ciphertext_blake2b) {
+            (Some(ciphertext), None) => unhex(ciphertext)?,
+            (None, Some(hash)) if hex(&blake2b(encrypted)?) == *hash => encrypted.to_vec(),
+            (None, Some(_)) => return Err(anyhow::anyhow!("the ciphertext's hash differs")),
+            _ => return Err(anyhow::anyhow!("give one of ciphertext or ciphertext_blake2b")),
+        };
+        if encrypted != expected {
+            return Err(anyhow::anyhow!("the ciphertext differs"));
+        }
+        Ok(expected)
+    }
+
+    /// Any threshold of the recipients can decrypt.
+    fn store(&self, skeys: &[key::SecretKey]) -> MemoryStore {
+        let mut store = MemoryStore::new();
+        for skey in &skeys[..self.threshold.unwrap_or(1) as usize] {
+            store.insert(skey.clone());
+        }
+        store
+    }
+}
+
+/// The plaintext of the vectors, whose byte `i` is `i % 251`, so that it does not repeat within a
+/// chunk on a power of two.
+fn plaintext(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8).collect()
+}
+
+fn blake2b(bytes: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut state = generichash::State::new(Some(32), None)
+        .map_err(|_| anyhow::anyhow!("unable to hash"))?;
+    state.update(bytes).map_err(|_| anyhow::anyhow!("unable to hash"))?;
+    Ok(state.finalize().map_err(|_| anyhow::anyhow!("unable to hash"))?.as_ref().to_vec())
+}
+
+fn version_from_str(version: &str) -> anyhow::Result<[u8; 3]> {
+    let parts = version.split('.').map(str::parse).collect::<Result<Vec<u8>, _>>()
+        .map_err(|_| anyhow::anyhow!("invalid version '{version}'"))?;
+    parts.try_into().map_err(|_| anyhow::anyhow!("invalid version '{version}'"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_selftest() {
+        let mut output = Vec::new();
+        selftest(None, &mut output).unwrap();
+        assert!(String::from_utf8(output).unwrap().ends_with("test vectors passed\n"));
+
+        // a vector which this build does not reproduce fails, naming the vector
+        let tampered = VECTORS.replacen("rng_seed = \"00", "rng_seed = \"01", 1);
+        let error = check(&tampered, &mut Vec::new()).err().unwrap();
+        assert!(error.to_string().starts_with("test vector '"));
+
+        // the major and minor versions must match the vectors'
+        let other_minor = VECTORS.replacen("6c6501000c", "6c6501010c", 1);
+        assert!(check(&other_minor, &mut Vec::new()).is_err());
+    }
+
+    /// The async adapters write and read the same streams as the sync ones, which `check` covers.
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn test_async_vectors() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use crate::async_stream::AsyncDecryptReader;
+
+        let vectors: Vectors = toml::from_str(VECTORS).unwrap();
+        let version = version_from_str(&vectors.version).unwrap();
+        for vector in &vectors.vector {
+            let skeys = vector.secret_keys().unwrap();
+            let plaintext = plaintext(vector.plaintext_len);
+            let mut encrypted = Vec::new();
+            vector.encryptor(&skeys).unwrap().encrypt(&mut plaintext.as_slice(), &mut encrypted)
+                .unwrap();
+            encrypted[PATCH_VERSION_OFFSET] = version[2];
+            let expected = vector.expected(&encrypted).unwrap();
+
+            if vector.threshold.is_none() {
+                let mut writer = vector.encryptor(&skeys).unwrap().async_writer(Vec::new()).unwrap();
+                writer.write_all(&plaintext).await.unwrap();
+                writer.shutdown().await.unwrap();
+                let mut written = writer.into_inner();
+                written[PATCH_VERSION_OFFSET] = version[2];
+                assert!(written == expected, "{}", vector.name);
+            }
+            let store = vector.store(&skeys);
+            let mut reader = AsyncDecryptReader::new(expected.as_slice(), &store).await.unwrap();
+            let mut read = Vec::new();
+            reader.read_to_end(&mut read).await.unwrap();
+            assert!(read == plaintext, "{}", vector.name);
+            assert!(reader.summary().unwrap().end_marker);
+        }
+    }
+}
+
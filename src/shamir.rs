@@ -0,0 +1,244 @@
+//! Shamir secret sharing of secret keys over GF(256), so that any `threshold` of the shares
+//! rebuild the key, and fewer reveal nothing about it.
+//!
+//! A share is text: `t7s-<threshold>-<index>-<base62 share>-<base62 public key>`.  The public key
+//! identifies which key the share belongs to, and checks the rebuilt key.
+
+use std::{fs, io::Write};
+
+use anyhow::Context;
+use sodiumoxide::crypto::box_::{PublicKey, SecretKey};
+use zeroize::Zeroizing;
+
+use crate::{base62, io::{create_key_file, disk_lookup_with, write_key_files, KeyAccess},
+            key, keystore::MemoryStore, rng::{OsRng, Rng}};
+
+const SHARE_PREFIX: &str = "t7s";
+
+/// One share of a secret key.
+pub struct Share {
+    pub threshold: u8,
+    /// The x coordinate, from 1 to the number of shares.
+    pub index: u8,
+    pub value: Zeroizing<[u8; 32]>,
+    pub public_key: PublicKey,
+}
+
+impl Share {
+    pub fn to_text(&self) -> Zeroizing<String> {
+        let value = Zeroizing::new(base62::encode(&self.value));
+        Zeroizing::new(format!("{SHARE_PREFIX}-{}-{}-{}-{}", self.threshold, self.index,
+                                            value.as_str(), base62::encode(&self.public_key.0)))
+    }
+
+    pub fn parse(text: &str) -> anyhow::Result<Share> {
+        let invalid = || anyhow::anyhow!("invalid share, which should look like \
+                                                    t7s-<threshold>-<index>-<share>-<public key>");
+        let fields: Vec<&str> = text.trim().split('-').collect();
+        let [prefix, threshold, index, value, public_key] = fields[..] else {
+            return Err(invalid());
+        };
+        if prefix != SHARE_PREFIX {
+            return Err(invalid());
+        }
+        let share = Share {
+            threshold: threshold.parse().map_err(|_| invalid())?,
+            index: index.parse().map_err(|_| invalid())?,
+            value: Zeroizing::new(base62::decode_exact(value)?),
+            public_key: public_key.parse::<key::PublicKey>()?.into(),
+        };
+        if share.index == 0 || share.threshold < 2 {
+            return Err(invalid());
+        }
+        Ok(share)
+    }
+}
+
+/// Split a secret key into `shares` shares, any `threshold` of which rebuild it.
+pub fn split(skey: &SecretKey, threshold: u8, shares: u8) -> anyhow::Result<Vec<Share>> {
+    let public_key = skey.public_key();
+    Ok(split_secret(&skey.0, threshold, shares, &mut OsRng)?.into_iter().zip(1..)
+        .map(|(value, index)| Share { threshold, index, value, public_key })
+        .collect())
+}
+
+/// Split 32 secret bytes into `shares` shares, whose indexes are 1 to `shares`.
+pub fn split_secret(secret: &[u8; 32], threshold: u8, shares: u8, rng: &mut dyn Rng)
+                                                    -> anyhow::Result<Vec<Zeroizing<[u8; 32]>>> {
+    if threshold < 2 || threshold > shares {
+        return Err(anyhow::anyhow!("the threshold must be at least 2, and at most the number of \
+                                                                                        shares"));
+    }
+    // each byte of the secret is the constant term of a random polynomial of degree threshold - 1
+    let mut coefficients = Zeroizing::new(vec![[0u8; 32]; threshold as usize]);
+    coefficients[0] = *secret;
+    for coefficient in &mut coefficients[1..] {
+        rng.fill(coefficient);
+    }
+    Ok((1..=shares).map(|index| {
+        let mut value = Zeroizing::new([0u8; 32]);
+        for (i, byte) in value.iter_mut().enumerate() {
+            // Horner's method, from the highest coefficient
+            *byte = coefficients.iter().rev()
+                .fold(0, |acc, coefficient| mul(acc, index) ^ coefficient[i]);
+        }
+        value
+    }).collect())
+}
+
+/// Rebuild a secret key from at least its threshold of shares.
+pub fn combine(shares: &[Share]) -> anyhow::Result<SecretKey> {
+    let first = shares.first().ok_or_else(|| anyhow::anyhow!("no shares"))?;
+    for share in shares {
+        if share.public_key != first.public_key || share.threshold != first.threshold {
+            return Err(anyhow::anyhow!("the shares are not all from the same split of one key"));
+        }
+    }
+    let indexed: Vec<(u8, &[u8; 32])> = shares.iter()
+        .map(|share| (share.index, &*share.value)).collect();
+    let skey = SecretKey(*combine_secret(&indexed, first.threshold)?);
+    if skey.public_key() != first.public_key {
+        return Err(anyhow::anyhow!("the shares do not rebuild the secret key for {}",
+                                                            base62::encode(&first.public_key.0)));
+    }
+    Ok(skey)
+}
+
+/// Rebuild 32 secret bytes from at least `threshold` (index, share) pairs.
+pub fn combine_secret(shares: &[(u8, &[u8; 32])], threshold: u8)
+                                                        -> anyhow::Result<Zeroizing<[u8; 32]>> {
+    let mut indexes: Vec<u8> = shares.iter().map(|(index, _)| *index).collect();
+    indexes.sort();
+    indexes.dedup();
+    if indexes.len() != shares.len() {
+        return Err(anyhow::anyhow!("the same share was given more than once"));
+    }
+    if shares.len() < threshold as usize {
+        return Err(anyhow::anyhow!("{} shares were given, but {threshold} are needed",
+                                                                                shares.len()));
+    }
+
+    // Lagrange interpolation at x = 0, of the first threshold shares
+    let shares = &shares[..threshold as usize];
+    let mut secret = Zeroizing::new([0u8; 32]);
+    for (index, value) in shares {
+        let mut basis = 1u8;
+        for (other, _) in shares.iter().filter(|(other, _)| other != index) {
+            basis = mul(basis, div(*other, other ^ index));
+        }
+        for (byte, value) in secret.iter_mut().zip(value.iter()) {
+            *byte ^= mul(basis, *value);
+        }
+    }
+    Ok(secret)
+}
+
+/// Multiply in GF(256), with the AES polynomial, without branching on secrets.
+fn mul(mut a: u8, mut b: u8) -> u8 {
+    let mut product = 0;
+    for _ in 0..8 {
+        product ^= a & 0u8.wrapping_sub(b & 1);
+        a = (a << 1) ^ (0x1b & 0u8.wrapping_sub(a >> 7));
+        b >>= 1;
+    }
+    product
+}
+
+/// Divide in GF(256), as a * b^254, since b^255 == 1.
+fn div(a: u8, b: u8) -> u8 {
+    let mut inverse = 1;
+    let mut power = b;
+    for _ in 0..7 {
+        power = mul(power, power);
+        inverse = mul(inverse, power);
+    }
+    mul(a, inverse)
+}
+
+/// Split a secret key in the key directory, printing the shares, or writing each into its own file
+/// in `out_dir`.
+pub fn split_key(keydir: &str, b62_pkey: &str, threshold: u8, shares: u8, out_dir: Option<&str>,
+                                access: KeyAccess, output: &mut dyn Write) -> anyhow::Result<()> {
+    let pkey: key::PublicKey = b62_pkey.parse()?;
+    let skey = disk_lookup_with(keydir, &pkey.into(), access)?;
+    for share in split(&skey, threshold, shares)? {
+        match out_dir {
+            Some(out_dir) => {
+                let path = format!("{out_dir}/{pkey}.share{}", share.index);
+                let mut file = create_key_file(&path, 0o600)?;
+                writeln!(file, "{}", share.to_text().as_str())
+                    .context(format!("unable to write a share into '{path}'"))?;
+                writeln!(output, "share {} of {shares} written into {path}", share.index)?;
+            },
+            None => writeln!(output, "{}", share.to_text().as_str())?,
+        }
+    }
+    Ok(())
+}
+
+/// Read shares from files, each containing one or more shares, one per line.
+pub fn read_shares(paths: &[String]) -> anyhow::Result<Vec<Share>> {
+    let mut shares = Vec::new();
+    for path in paths {
+        let contents = Zeroizing::new(fs::read_to_string(path)
+            .context(format!("unable to read shares from '{path}'"))?);
+        shares.extend(parse_shares(&contents).context(format!("invalid share in '{path}'"))?);
+    }
+    Ok(shares)
+}
+
+/// Parse shares, one per line, ignoring blank lines.
+pub fn parse_shares(text: &str) -> anyhow::Result<Vec<Share>> {
+    text.lines().filter(|line| !line.trim().is_empty()).map(Share::parse).collect()
+}
+
+/// Rebuild a secret key from shares, writing it into the key directory.
+pub fn combine_key(keydir: &str, shares: &[Share], output: &mut dyn Write) -> anyhow::Result<()> {
+    let skey = combine(shares)?;
+    let (path, _public_path) = write_key_files(keydir, &skey.public_key(), &skey, None, None)?;
+    writeln!(output, "rebuilt secret key into {path}")?;
+    Ok(())
+}
+
+/// Rebuild a secret key from shares, only in memory, for a single decryption.
+pub fn combine_store(shares: &[Share]) -> anyhow::Result<MemoryStore> {
+    let mut store = MemoryStore::new();
+    store.insert(combine(shares)?.into());
+    Ok(store)
+}
+
+#[cfg(test)]
+mod tests {
+    use sodiumoxide::crypto::box_;
+    use super::*;
+
+    #[test]
+    fn test_split_and_combine() {
+        let (_, skey) = box_::gen_keypair();
+        let shares: Vec<Share> = split(&skey, 3, 5).unwrap().iter()
+            .map(|share| Share::parse(&share.to_text()).unwrap()).collect();
+
+        assert_eq!(combine(&shares).unwrap(), skey);
+        let [a, b, c, d, e] = &shares[..] else { panic!() };
+        for subset in [[a, b, c], [e, c, a], [b, d, e]] {
+            let subset: Vec<Share> = subset.iter().map(|share| Share::parse(&share.to_text())
+                                                                        .unwrap()).collect();
+            assert_eq!(combine(&subset).unwrap(), skey);
+        }
+
+        let two = parse_shares(&format!("{}\n{}\n", a.to_text().as_str(),
+                                                            b.to_text().as_str())).unwrap();
+        assert_eq!(combine(&two).err().unwrap().to_string(),
+                                                    "2 shares were given, but 3 are needed");
+        assert!(split(&skey, 1, 5).is_err());
+        assert!(split(&skey, 4, 3).is_err());
+    }
+
+    #[test]
+    fn test_gf256() {
+        assert_eq!(mul(0x57, 0x83), 0xc1); // from FIPS 197
+        for a in 1..=255 {
+            assert_eq!(mul(div(1, a), a), 1);
+        }
+    }
+}
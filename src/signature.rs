@@ -0,0 +1,99 @@
+//! Signatures made with target keys, so that sources can check what a target publishes (such as
+//! its epoch manifests) knowing only its base62 public key.
+//!
+//! X25519 keys cannot sign directly, so this is XEdDSA, from Signal's specification.  The secret
+//! key is used as an Ed25519 scalar, and the signature verifies as an ordinary Ed25519 signature
+//! under the Edwards form of the public key, whose sign bit is always 0.
+
+use curve25519_dalek::{montgomery::MontgomeryPoint, EdwardsPoint, Scalar};
+use sodiumoxide::{crypto::{box_::{PublicKey, SecretKey}, hash::sha512, sign::ed25519},
+                  randombytes};
+use zeroize::Zeroizing;
+
+use crate::common::{hex, unhex};
+
+pub const SIGNATURE_BYTES: usize = 64;
+
+/// An XEdDSA signature, which is an Ed25519 signature: R then s.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Signature(pub [u8; SIGNATURE_BYTES]);
+
+impl Signature {
+    pub fn to_hex(&self) -> String {
+        hex(&self.0)
+    }
+
+    pub fn from_hex(text: &str) -> anyhow::Result<Signature> {
+        let bytes = unhex(text)?;
+        Ok(Signature(bytes.try_into()
+            .map_err(|_| anyhow::anyhow!("a signature is {SIGNATURE_BYTES} bytes"))?))
+    }
+}
+
+/// Sign `message` with a target secret key.
+pub fn sign(skey: &SecretKey, message: &[u8]) -> Signature {
+    // X25519 clamps the secret key before using it as a scalar
+    let mut clamped = Zeroizing::new(skey.0);
+    clamped[0] &= 248;
+    clamped[31] &= 127;
+    clamped[31] |= 64;
+    let mut a = Zeroizing::new(Scalar::from_bytes_mod_order(*clamped));
+    let mut edwards_pkey = EdwardsPoint::mul_base(&a).compress().0;
+    // the verifier can only know the Edwards public key with a sign bit of 0
+    if edwards_pkey[31] & 0x80 != 0 {
+        *a = -*a;
+        edwards_pkey[31] &= 0x7f;
+    }
+
+    let mut random = Zeroizing::new([0u8; 64]);
+    randombytes::randombytes_into(&mut *random);
+    let mut state = sha512::State::new();
+    state.update(&[0xfe]);
+    state.update(&[0xff; 31]);
+    state.update(a.as_bytes());
+    state.update(message);
+    state.update(&*random);
+    let r = Zeroizing::new(Scalar::from_bytes_mod_order_wide(&state.finalize().0));
+    let big_r = EdwardsPoint::mul_base(&r).compress().0;
+
+    let mut state = sha512::State::new();
+    state.update(&big_r);
+    state.update(&edwards_pkey);
+    state.update(message);
+    let h = Scalar::from_bytes_mod_order_wide(&state.finalize().0);
+    let s = h * *a + *r;
+
+    let mut signature = [0u8; SIGNATURE_BYTES];
+    signature[..32].copy_from_slice(&big_r);
+    signature[32..].copy_from_slice(s.as_bytes());
+    Signature(signature)
+}
+
+/// Check that `signature` was made over `message` by the secret key for `pkey`.
+pub fn verify(pkey: &PublicKey, message: &[u8], signature: &Signature) -> anyhow::Result<()> {
+    let invalid = || anyhow::anyhow!("invalid signature");
+    let edwards_pkey = MontgomeryPoint(pkey.0).to_edwards(0).ok_or_else(invalid)?.compress();
+    let signature = ed25519::Signature::from_bytes(&signature.0).map_err(|_| invalid())?;
+    if !ed25519::verify_detached(&signature, message, &ed25519::PublicKey(edwards_pkey.0)) {
+        return Err(invalid());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use sodiumoxide::crypto::box_;
+    use super::*;
+
+    #[test]
+    fn test_sign_and_verify() {
+        for _ in 0..8 { // so that both signs of the Edwards public key are covered
+            let (pkey, skey) = box_::gen_keypair();
+            let signature = sign(&skey, b"Mary had a little lamb");
+            assert!(verify(&pkey, b"Mary had a little lamb", &signature).is_ok());
+            assert!(verify(&pkey, b"Mary had a little lamp", &signature).is_err());
+            assert!(verify(&box_::gen_keypair().0, b"Mary had a little lamb", &signature).is_err());
+            assert_eq!(Signature::from_hex(&signature.to_hex()).unwrap(), signature);
+        }
+    }
+}
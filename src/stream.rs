@@ -0,0 +1,290 @@
+//! `Write` and `Read` adapters, for services which encrypt or decrypt as they go, rather than
+//! handing over a whole input and output.
+
+use std::io::{self, Read, Write};
+
+use sodiumoxide::crypto::box_::{self, Nonce, PrecomputedKey, PublicKey, SecretKey};
+
+use crate::{common::*, decrypt::{read_chunk, read_symkey, Chunk, Summary},
+            encrypt::{write_chunk, write_header}, key, keystore::KeyStore, memory::Locked};
+
+/// Encrypts everything written to it, in the same format as `encrypt::encrypt`.
+///
+/// Plaintext is buffered until there is a whole chunk, or until `flush`, which writes a shorter
+/// chunk.  `finish` writes the end marker, as does dropping the writer (ignoring any error).
+/// Once writing a chunk to the output fails, every later call fails, and dropping the writer
+/// writes nothing.
+pub struct EncryptWriter<W: Write> {
+    /// None once finished.
+    output: Option<W>,
+    symkey: Locked<PrecomputedKey>,
+    initial_nonce: Nonce,
+    chunk_num: u64,
+    buf: Locked<[u8]>,
+    len: usize,
+    /// A chunk failed to write.  It was sealed in place, so it must never be sealed again, which
+    /// would turn it back into plaintext.
+    failed: bool,
+}
+
+impl<W: Write> EncryptWriter<W> {
+    /// Write the header for `target_key`, from a new ephemeral keypair.  (`Encryptor::writer` can
+    /// draw the ephemeral key and initial nonce from another `Rng`.)
+    pub fn new(output: W, target_key: &key::PublicKey) -> io::Result<Self> {
+        EncryptWriter::with_keys(output, &(*target_key).into(), &box_::gen_keypair().1,
+                                                                            &box_::gen_nonce())
+    }
+
+    pub(crate) fn with_keys(mut output: W, target_pkey: &PublicKey, source_skey: &SecretKey,
+                                                    initial_nonce: &Nonce) -> io::Result<Self> {
+        let symkey = Locked::new(Box::new(box_::precompute(target_pkey, source_skey)));
+        write_header(&source_skey.public_key(), target_pkey, initial_nonce, &mut output)
+            .map_err(io::Error::other)?;
+        Ok(EncryptWriter {
+            output: Some(output),
+            symkey,
+            initial_nonce: *initial_nonce,
+            chunk_num: 0,
+            buf: Locked::buffer(MAX_PLAINTEXT_CHUNK),
+            len: 0,
+            failed: false,
+        })
+    }
+
+    /// Write any buffered plaintext, and the end marker, returning the inner writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        // the output is taken even if the end fails, so that dropping the writer does not write
+        // a second end marker after a partial one
+        let result = self.write_end();
+        let output = self.output.take().expect("only finish takes the output");
+        result.map(|()| output)
+    }
+
+    fn check_failed(&self) -> io::Result<()> {
+        match self.failed {
+            true => Err(io::Error::other("an earlier chunk failed to write")),
+            false => Ok(()),
+        }
+    }
+
+    fn write_buffered(&mut self) -> io::Result<()> {
+        self.check_failed()?;
+        if self.len == 0 {
+            return Ok(());
+        }
+        let output = self.output.as_mut().expect("not finished");
+        let chunk_nonce = calculate_chunk_nonce(&self.initial_nonce, self.chunk_num);
+        let result = write_chunk(&mut self.buf[..self.len], &chunk_nonce, &self.symkey, output);
+        // the buffer now holds ciphertext, whether or not it was written
+        self.chunk_num += 1;
+        self.len = 0;
+        self.failed = result.is_err();
+        result.map_err(io::Error::other)
+    }
+
+    fn write_end(&mut self) -> io::Result<()> {
+        self.write_buffered()?;
+        let output = self.output.as_mut().expect("not finished");
+        output.write_all(&0u16.to_be_bytes())?; // 0x0000 signifies end
+        output.flush()
+    }
+}
+
+impl<W: Write> Write for EncryptWriter<W> {
+    fn write(&mut self, plaintext: &[u8]) -> io::Result<usize> {
+        self.check_failed()?;
+        if self.len == self.buf.len() {
+            self.write_buffered()?;
+        }
+        let n = plaintext.len().min(self.buf.len() - self.len);
+        self.buf[self.len..self.len + n].copy_from_slice(&plaintext[..n]);
+        self.len += n;
+        Ok(n)
+    }
+
+    /// Write the buffered plaintext as a chunk, so that everything written so far can be decrypted.
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_buffered()?;
+        self.output.as_mut().expect("not finished").flush()
+    }
+}
+
+impl<W: Write> Drop for EncryptWriter<W> {
+    fn drop(&mut self) {
+        if self.output.is_some() && !self.failed {
+            let _ = self.write_end();
+        }
+    }
+}
+
+/// Decrypts a stream as it is read, in either format.
+///
+/// Each chunk is authenticated before any of its plaintext is returned.  A stream which ends
+/// without the end marker reads as ending normally, so check `summary` afterwards.
+pub struct DecryptReader<R: Read> {
+    input: R,
+    symkey: Locked<PrecomputedKey>,
+    initial_nonce: Nonce,
+    chunk_num: u64,
+    buf: Locked<[u8]>,
+    /// The unread plaintext in `buf`.
+    start: usize,
+    end: usize,
+    /// Set once the stream has ended, with whether it had the end marker.
+    ended: Option<bool>,
+}
+
+impl<R: Read> DecryptReader<R> {
+    /// Read the header, finding the precomputed key in `store`.
+    pub fn new(mut input: R, store: &dyn KeyStore) -> anyhow::Result<Self> {
+        let (symkey, initial_nonce) = read_symkey(&mut input, store)?;
+        Ok(DecryptReader {
+            input,
+            symkey,
+            initial_nonce,
+            chunk_num: 0,
+            buf: Locked::buffer(MAX_CIPHERTEXT_CHUNK),
+            start: 0,
+            end: 0,
+            ended: None,
+        })
+    }
+
+    /// How the stream ended, once `read` has returned 0.
+    pub fn summary(&self) -> Option<Summary> {
+        self.ended.map(|end_marker| Summary { chunks: self.chunk_num, end_marker })
+    }
+
+    pub fn into_inner(self) -> R {
+        self.input
+    }
+}
+
+impl<R: Read> Read for DecryptReader<R> {
+    fn read(&mut self, plaintext: &mut [u8]) -> io::Result<usize> {
+        while self.start == self.end {
+            if self.ended.is_some() {
+                return Ok(0);
+            }
+            match read_chunk(&mut self.input, &mut self.buf, &self.symkey, &self.initial_nonce,
+                                                                            self.chunk_num)? {
+                Chunk::Plaintext(len) => {
+                    self.chunk_num += 1;
+                    self.start = BOX_OVERHEAD;
+                    self.end = BOX_OVERHEAD + len;
+                },
+                Chunk::End { end_marker } => self.ended = Some(end_marker),
+            }
+        }
+        let n = plaintext.len().min(self.end - self.start);
+        plaintext[..n].copy_from_slice(&self.buf[self.start..self.start + n]);
+        self.start += n;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{decrypt::Decryptor, encrypt::Encryptor, keystore::MemoryStore, rng::SeededRng};
+    use super::*;
+
+    #[test]
+    fn test_encrypt_writer_and_decrypt_reader() {
+        let target_skey = key::SecretKey::generate();
+        let target_pkey = target_skey.public_key();
+        let mut store = MemoryStore::new();
+        store.insert(target_skey.clone());
+        let plaintext: Vec<u8> = (0..150_000u32).map(|i| i as u8).collect();
+
+        // written in pieces, the writer's output is the same as Encryptor::encrypt's
+        let encryptor = || Encryptor::new(target_pkey).rng(SeededRng::new([5u8; 32]));
+        let mut writer = encryptor().writer(Vec::new()).unwrap();
+        for piece in plaintext.chunks(1000) {
+            writer.write_all(piece).unwrap();
+        }
+        let encrypted = writer.finish().unwrap();
+        let mut expected = Vec::new();
+        encryptor().encrypt(&mut plaintext.as_slice(), &mut expected).unwrap();
+        assert_eq!(encrypted, expected);
+
+        let mut reader = DecryptReader::new(encrypted.as_slice(), &store).unwrap();
+        let mut decrypted = Vec::new();
+        reader.read_to_end(&mut decrypted).unwrap();
+        assert_eq!(decrypted, plaintext);
+        let summary = reader.summary().unwrap();
+        assert_eq!(summary.chunks, 3);
+        assert!(summary.end_marker);
+
+        // each flush ends a chunk, and dropping the writer writes the end marker
+        let mut encrypted = Vec::new();
+        {
+            let mut writer = EncryptWriter::new(&mut encrypted, &target_pkey).unwrap();
+            writer.write_all(b"Mary had ").unwrap();
+            writer.flush().unwrap();
+            writer.write_all(b"a little lamb").unwrap();
+        }
+        let mut decrypted = Vec::new();
+        let summary = Decryptor::new(&store).decrypt(&mut encrypted.as_slice(), &mut decrypted)
+            .unwrap();
+        assert_eq!(decrypted, b"Mary had a little lamb");
+        assert_eq!(summary.chunks, 2);
+        assert!(summary.end_marker);
+
+        // a tampered chunk is an error, rather than plaintext
+        let last = encrypted.len() - 3;
+        encrypted[last] ^= 1;
+        let mut reader = DecryptReader::new(encrypted.as_slice(), &store).unwrap();
+        let error = reader.read_to_end(&mut Vec::new()).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(error.to_string(), "bad ciphertext in chunk 1");
+
+        // when finish fails to write the end marker, dropping the writer does not try again
+        let mut output = FailsOnce::new(|bytes| bytes == [0, 0]);
+        let mut writer = EncryptWriter::new(&mut output, &target_pkey).unwrap();
+        writer.write_all(b"Mary had a little lamb").unwrap();
+        assert!(writer.finish().is_err());
+        assert_eq!(output.attempts, 1);
+
+        // nor, when a chunk fails to write, do later flushes or the drop seal it again, which would
+        // write the plaintext
+        let mut output = FailsOnce::new(|bytes| bytes.len() == 2 && bytes != [0, 0]);
+        let mut writer = EncryptWriter::new(&mut output, &target_pkey).unwrap();
+        writer.write_all(b"Mary had a little lamb").unwrap();
+        assert!(writer.flush().is_err());
+        assert!(writer.flush().is_err());
+        assert!(writer.write(b"!").is_err());
+        drop(writer);
+        assert_eq!(output.attempts, 1);
+        assert!(!output.written.windows(22).any(|window| window == b"Mary had a little lamb"));
+    }
+
+    /// Fails the first write which `fails` picks out, counting the attempts at such writes.
+    struct FailsOnce {
+        fails: fn(&[u8]) -> bool,
+        attempts: usize,
+        written: Vec<u8>,
+    }
+
+    impl FailsOnce {
+        fn new(fails: fn(&[u8]) -> bool) -> Self {
+            FailsOnce { fails, attempts: 0, written: Vec::new() }
+        }
+    }
+
+    impl Write for FailsOnce {
+        fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+            if (self.fails)(bytes) {
+                self.attempts += 1;
+                if self.attempts == 1 {
+                    return Err(io::Error::other("no space left"));
+                }
+            }
+            self.written.extend_from_slice(bytes);
+            Ok(bytes.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,220 @@
+//! Threshold streams, which any `threshold` of several recipients must cooperate to decrypt.
+//!
+//! The chunks are enciphered with a random content key, rather than one precomputed from a pair of
+//! keys.  The content key is split into one Shamir share per recipient, and each share is boxed
+//! from the ephemeral source key to that recipient's public key.
+
+use std::io::{Read, Write};
+
+use anyhow::Context;
+use sodiumoxide::crypto::box_::{self, Nonce, PrecomputedKey, PublicKey, SecretKey};
+use zeroize::Zeroizing;
+
+use crate::{base62, common::*, encrypt::{write_chunks, Encryptor}, error::Error, key,
+            keystore::KeyStore, memory::Locked, rng::Rng, shamir};
+
+/// A boxed share of the content key: its tag, then the enciphered share.
+pub const SEALED_SHARE: usize = BOX_OVERHEAD + 32;
+
+/// The header of a threshold stream, following the magic, protocol and version.
+pub struct Recipients {
+    pub threshold: u8,
+    pub source_pkey: PublicKey,
+    pub initial_nonce: Nonce,
+    /// Each recipient's public key and sealed share, in order of share index from 1.
+    pub entries: Vec<(PublicKey, [u8; SEALED_SHARE])>,
+}
+
+pub fn encrypt(target_public_keys: &[String], threshold: u8, input: &mut dyn Read,
+                                                    output: &mut dyn Write) -> anyhow::Result<()> {
+    let target_keys = target_public_keys.iter()
+        .map(|key| key.parse())
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    Encryptor::threshold(&target_keys, threshold).encrypt(input, output)
+}
+
+/// Write a threshold stream, drawing its content key, then the coefficients of its shares, from
+/// `rng`.
+pub(crate) fn write_stream(target_pkeys: &[PublicKey], threshold: u8, source_skey: &SecretKey,
+            initial_nonce: &Nonce, rng: &mut dyn Rng, input: &mut dyn Read,
+            output: &mut dyn Write) -> anyhow::Result<()> {
+    let shares = u8::try_from(target_pkeys.len())
+        .map_err(|_| anyhow::anyhow!("a threshold stream has at most 255 recipients"))?;
+    for (i, pkey) in target_pkeys.iter().enumerate() {
+        if target_pkeys[..i].contains(pkey) {
+            return Err(anyhow::anyhow!("{} is a recipient more than once", base62::encode(&pkey.0)));
+        }
+    }
+
+    let mut symkey = Locked::new(Box::new(PrecomputedKey([0u8; 32])));
+    rng.fill(&mut symkey.0);
+
+    // the share keys all differ, so each may use the initial nonce
+    let entries = shamir::split_secret(&symkey.0, threshold, shares, rng)?.into_iter()
+        .zip(target_pkeys)
+        .map(|(mut share, target_pkey)| {
+            let share_key = Locked::new(Box::new(box_::precompute(target_pkey, source_skey)));
+            let tag = box_::seal_detached_precomputed(&mut *share, initial_nonce, &share_key);
+            let mut sealed = [0u8; SEALED_SHARE];
+            sealed[..BOX_OVERHEAD].copy_from_slice(&tag.0);
+            sealed[BOX_OVERHEAD..].copy_from_slice(&*share);
+            (*target_pkey, sealed)
+        })
+        .collect();
+    let recipients = Recipients {
+        threshold, source_pkey: source_skey.public_key(), initial_nonce: *initial_nonce, entries,
+    };
+
+    recipients.write(output)?;
+    write_chunks(&symkey, initial_nonce, input, output)
+}
+
+impl Recipients {
+    pub fn write(&self, output: &mut dyn Write) -> anyhow::Result<()> {
+        output.write_all(FADEDBEE)?;
+        output.write_all(THRESHOLD)?;
+        output.write_all(&version_bytes())?;
+        output.write_all(&[self.threshold, self.entries.len() as u8])?;
+        output.write_all(&self.source_pkey.0)?;
+        output.write_all(&self.initial_nonce.0)?;
+        for (target_pkey, sealed) in &self.entries {
+            output.write_all(&target_pkey.0)?;
+            output.write_all(sealed)?;
+        }
+        Ok(())
+    }
+
+    /// Read the rest of a threshold header, after the preamble.
+    pub fn read(input: &mut dyn Read) -> anyhow::Result<Recipients> {
+        let mut counts = [0u8; 2];
+        input.read_exact(&mut counts).map_err(Error::in_header)?;
+        let [threshold, shares] = counts;
+        if threshold < 2 || threshold > shares {
+            return Err(Error::InvalidThreshold { threshold, shares }.into());
+        }
+
+        let mut source_pkey = PublicKey([0u8; 32]);
+        input.read_exact(&mut source_pkey.0).map_err(Error::in_header)?;
+        let mut initial_nonce = Nonce([0u8; 24]);
+        input.read_exact(&mut initial_nonce.0).map_err(Error::in_header)?;
+
+        let mut entries = Vec::with_capacity(shares as usize);
+        for _ in 0..shares {
+            let mut target_pkey = PublicKey([0u8; 32]);
+            input.read_exact(&mut target_pkey.0).map_err(Error::in_header)?;
+            let mut sealed = [0u8; SEALED_SHARE];
+            input.read_exact(&mut sealed).map_err(Error::in_header)?;
+            entries.push((target_pkey, sealed));
+        }
+        Ok(Recipients { threshold, source_pkey, initial_nonce, entries })
+    }
+
+    pub fn target_pkeys(&self) -> Vec<PublicKey> {
+        self.entries.iter().map(|(target_pkey, _)| *target_pkey).collect()
+    }
+
+    /// Open the shares of every recipient in `store`, until there are enough to rebuild the
+    /// content key.
+    pub fn content_key(&self, store: &dyn KeyStore) -> anyhow::Result<Locked<PrecomputedKey>> {
+        let mut shares: Vec<(u8, Zeroizing<[u8; 32]>)> = Vec::new();
+        let mut last_error = None;
+        for ((target_pkey, sealed), index) in self.entries.iter().zip(1..) {
+            if shares.len() == self.threshold as usize {
+                break;
+            }
+            let target_key = key::PublicKey::from(*target_pkey);
+            let share_key = match store.precompute(&self.source_pkey.into(), &target_key) {
+                Ok(share_key) => Locked::new(Box::new((&share_key).into())),
+                Err(e) => { last_error = Some(e); continue },
+            };
+            let tag = box_::Tag::from_slice(&sealed[..BOX_OVERHEAD])
+                .expect("BOX_OVERHEAD is the tag size");
+            let mut share = Zeroizing::new([0u8; 32]);
+            share.copy_from_slice(&sealed[BOX_OVERHEAD..]);
+            // a share which fails to open is passed over, like a key which is not in the store
+            if box_::open_detached_precomputed(&mut *share, &tag, &self.initial_nonce, &share_key)
+                                                                                    .is_err() {
+                let key = base62::encode(&target_pkey.0);
+                last_error = Some(anyhow::Error::new(Error::BadShare { key }));
+                continue;
+            }
+            shares.push((index, share));
+        }
+
+        if shares.len() < self.threshold as usize {
+            let error = anyhow::anyhow!("only {} of the {} recipient keys needed are available",
+                                                                    shares.len(), self.threshold);
+            return Err(match last_error {
+                Some(e) => e.context(error),
+                None => error,
+            });
+        }
+        let indexed: Vec<(u8, &[u8; 32])> = shares.iter()
+            .map(|(index, share)| (*index, &**share)).collect();
+        let content_key = shamir::combine_secret(&indexed, self.threshold)
+            .context("unable to rebuild the content key")?;
+        Ok(Locked::new(Box::new(PrecomputedKey(*content_key))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{decrypt::Decryptor, inspect::scan, keystore::MemoryStore, rng::SeededRng};
+    use super::*;
+
+    #[test]
+    fn test_threshold() {
+        let skeys: Vec<key::SecretKey> = (0..3).map(|_| key::SecretKey::generate()).collect();
+        let target_keys: Vec<key::PublicKey> = skeys.iter().map(key::SecretKey::public_key)
+            .collect();
+        let mut encrypted = Vec::<u8>::new();
+        Encryptor::threshold(&target_keys, 2).rng(SeededRng::new([9u8; 32]))
+            .encrypt(&mut b"Mary had a little lamb".as_slice(), &mut encrypted).unwrap();
+        assert_eq!(encrypted.len(), 16 + 2 + 32 + 24 + 3 * (32 + 48) + 2 + 22 + 16 + 2);
+
+        let inspection = scan(&mut encrypted.as_slice()).unwrap();
+        assert_eq!(inspection.header.threshold, Some(2));
+        assert_eq!(inspection.header.target_keys, target_keys);
+
+        for pair in [[0, 1], [2, 0], [1, 2]] {
+            let mut store = MemoryStore::new();
+            for i in pair {
+                store.insert(skeys[i].clone());
+            }
+            let mut decrypted = Vec::<u8>::new();
+            let summary = Decryptor::new(&store).decrypt(&mut encrypted.as_slice(), &mut decrypted)
+                .unwrap();
+            assert_eq!(decrypted, b"Mary had a little lamb");
+            assert!(summary.end_marker);
+        }
+
+        let mut store = MemoryStore::new();
+        store.insert(skeys[1].clone());
+        let error = Decryptor::new(&store).decrypt(&mut encrypted.as_slice(), &mut Vec::new())
+            .err().unwrap();
+        assert_eq!(error.to_string(), "only 1 of the 2 recipient keys needed are available");
+
+        // a tampered share is passed over, as long as enough others open
+        let mut tampered = encrypted.clone();
+        tampered[16 + 2 + 32 + 24 + 32] ^= 1;
+        let mut store = MemoryStore::new();
+        for skey in &skeys {
+            store.insert(skey.clone());
+        }
+        let mut decrypted = Vec::<u8>::new();
+        Decryptor::new(&store).decrypt(&mut tampered.as_slice(), &mut decrypted).unwrap();
+        assert_eq!(decrypted, b"Mary had a little lamb");
+        tampered[16 + 2 + 32 + 24 + (32 + 48) + 32] ^= 1;
+        let error = Decryptor::new(&store).decrypt(&mut tampered.as_slice(), &mut Vec::new())
+            .err().unwrap();
+        assert!(matches!(Error::find(&error), Some(Error::BadShare { .. })));
+        tampered = encrypted.clone();
+        tampered[16] = 4;
+        let error = Decryptor::new(&store).decrypt(&mut tampered.as_slice(), &mut Vec::new())
+            .err().unwrap();
+        assert!(matches!(Error::find(&error), Some(Error::InvalidThreshold { threshold: 4, .. })));
+
+        assert!(Encryptor::threshold(&[target_keys[0]; 2], 2)
+                            .encrypt(&mut b"".as_slice(), &mut Vec::new()).is_err());
+    }
+}
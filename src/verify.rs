@@ -0,0 +1,73 @@
+use std::io::{self, Read, Write};
+
+use crate::{decrypt::Decryptor, error::Error, inspect::scan, keystore::KeyStore};
+
+/// Authenticate every chunk, discarding the plaintext, and report the result.
+pub fn verify(store: &dyn KeyStore, input: &mut dyn Read, output: &mut dyn Write)
+                                                                        -> anyhow::Result<()> {
+    let summary = Decryptor::new(store).require_end_marker(true).decrypt(input, &mut io::sink())?;
+    writeln!(output, "OK: {} chunks authenticated", summary.chunks)?;
+    Ok(())
+}
+
+/// Check only the framing, which needs no secret key, so that sources can check what they wrote.
+pub fn verify_structure(input: &mut dyn Read, output: &mut dyn Write) -> anyhow::Result<()> {
+    let inspection = scan(input)?;
+    if inspection.truncated {
+        return Err(Error::Truncated { chunk: inspection.chunks }.into());
+    }
+    if !inspection.end_marker {
+        return Err(Error::NoEndMarker { chunks: inspection.chunks }.into());
+    }
+    writeln!(output, "OK: {} chunks, {} bytes of ciphertext (structure only, not authenticated)",
+        inspection.chunks, inspection.ciphertext_bytes)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{encrypt::Encryptor, key::SecretKey, keystore::MemoryStore};
+    use super::*;
+
+    #[test]
+    fn test_verify() {
+        let target_skey = SecretKey::generate();
+        let plaintext = b"Mary had a little lamb";
+        let mut encrypted = Vec::new();
+        Encryptor::new(target_skey.public_key())
+            .encrypt(&mut &plaintext[..], &mut encrypted).unwrap();
+        let mut store = MemoryStore::new();
+        store.insert(target_skey);
+        let check = |encrypted: &[u8]| {
+            let mut output = Vec::new();
+            let full = verify(&store, &mut &encrypted[..], &mut output)
+                .map(|()| String::from_utf8(output).unwrap());
+            let mut output = Vec::new();
+            let structure = verify_structure(&mut &encrypted[..], &mut output)
+                .map(|()| String::from_utf8(output).unwrap());
+            (full, structure)
+        };
+        let error = |result: anyhow::Result<String>| Error::find(&result.err().unwrap())
+            .map(ToString::to_string);
+
+        let (full, structure) = check(&encrypted);
+        assert_eq!(full.unwrap(), "OK: 1 chunks authenticated\n");
+        assert_eq!(structure.unwrap(), format!("OK: 1 chunks, {} bytes of ciphertext (structure \
+                                    only, not authenticated)\n", plaintext.len() + 16));
+
+        let (full, structure) = check(&encrypted[..encrypted.len() - 5]);
+        assert_eq!(error(full), Some(Error::Truncated { chunk: 0 }.to_string()));
+        assert_eq!(error(structure), Some(Error::Truncated { chunk: 0 }.to_string()));
+
+        let (full, structure) = check(&encrypted[..encrypted.len() - 2]);
+        assert_eq!(error(full), Some(Error::NoEndMarker { chunks: 1 }.to_string()));
+        assert_eq!(error(structure), Some(Error::NoEndMarker { chunks: 1 }.to_string()));
+
+        // the tag follows the header and the chunk's length
+        let mut tampered = encrypted.clone();
+        tampered[encrypted.len() - 2 - plaintext.len() - 16] ^= 1;
+        let (full, structure) = check(&tampered);
+        assert_eq!(error(full), Some(Error::Authentication { chunk: 0 }.to_string()));
+        assert!(structure.unwrap().starts_with("OK: 1 chunks"));
+    }
+}